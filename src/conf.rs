@@ -5,7 +5,9 @@
 // src/conf.rs
 // Manage configure file.
 
+use crate::constants;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use std::sync::Arc;
@@ -17,19 +19,205 @@ pub struct ConfigSmtpServerExpand {
     pub pipe_tx_len: usize,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConfigSmtpServerEsmtp {
+    pub pipelining: bool,
+    pub eight_bit_mime: bool,
+    pub chunking: bool,
+    pub binary_mime: bool,
+    pub smtputf8: bool,
+    pub dsn: bool,
+    pub requiretls: bool,
+    pub futurerelease: bool,
+    pub deliverby: bool,
+    pub mt_priority: bool,
+    pub help: bool,
+}
+
+impl Default for ConfigSmtpServerEsmtp {
+    fn default() -> Self {
+        Self {
+            pipelining: true,
+            eight_bit_mime: true,
+            chunking: true,
+            binary_mime: true,
+            smtputf8: true,
+            dsn: true,
+            requiretls: true,
+            futurerelease: true,
+            deliverby: true,
+            mt_priority: true,
+            help: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigSmtpServerCert {
+    pub domain: String,
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigSmtpServerTls {
+    pub cert: String,
+    pub key: String,
+    #[serde(default)]
+    pub certs: Vec<ConfigSmtpServerCert>,
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    constants::SMTPD_TLS_RELOAD_INTERVAL
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigSmtpServerUser {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConfigSmtpServerAuth {
+    pub require_tls: bool,
+    pub mechanisms: Vec<String>,
+    pub users: Vec<ConfigSmtpServerUser>,
+}
+
+impl Default for ConfigSmtpServerAuth {
+    fn default() -> Self {
+        Self {
+            require_tls: true,
+            mechanisms: vec![String::from("PLAIN"), String::from("LOGIN")],
+            users: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConfigSmtpServerDisclosure {
+    #[default]
+    Disabled,
+    Authenticated,
+    Full,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigMailbox {
+    pub address: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigAlias {
+    pub address: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConfigDirectory {
+    pub mailboxes: Vec<ConfigMailbox>,
+    pub aliases: Vec<ConfigAlias>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConfigMailboxFormat {
+    #[default]
+    Maildir,
+    Mbox,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigSmtpServerDelivery {
+    #[serde(default)]
+    pub mail_root: String,
+    #[serde(default)]
+    pub format: ConfigMailboxFormat,
+    // "unix:/path" or "host:port", hands mail to an LMTP server instead.
+    #[serde(default)]
+    pub lmtp: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConfigSmtpServerHelp {
+    pub text: Vec<String>,
+    pub topics: HashMap<String, Vec<String>>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ConfigSmtpServer {
     pub domain: String,
     pub binds: Vec<String>,
     pub ports: Vec<u16>,
+    #[serde(default)]
+    pub tls_ports: Vec<u16>,
+    #[serde(default)]
+    pub lmtp_ports: Vec<u16>,
     pub hello: String,
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    #[serde(default = "default_max_hold_time")]
+    pub max_hold_time: u64,
+    #[serde(default)]
+    pub esmtp: ConfigSmtpServerEsmtp,
+    #[serde(default)]
+    pub tls: Option<ConfigSmtpServerTls>,
+    #[serde(default)]
+    pub auth: Option<ConfigSmtpServerAuth>,
+    #[serde(default)]
+    pub disclosure: ConfigSmtpServerDisclosure,
+    #[serde(default)]
+    pub directory: ConfigDirectory,
+    #[serde(default)]
+    pub delivery: Option<ConfigSmtpServerDelivery>,
+    #[serde(default)]
+    pub help: ConfigSmtpServerHelp,
     pub expand: ConfigSmtpServerExpand,
 }
 
+fn default_max_message_size() -> usize {
+    constants::SMTPD_MAX_MESSAGE_SIZE
+}
+
+fn default_max_hold_time() -> u64 {
+    constants::SMTPD_MAX_HOLD_TIME
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConfigQueue {
+    pub path: String,
+}
+
+impl Default for ConfigQueue {
+    fn default() -> Self {
+        Self {
+            path: constants::QUEUE_PATH.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
+    #[serde(default)]
+    pub queue: ConfigQueue,
     pub smtp_servers: Vec<Arc<ConfigSmtpServer>>,
 }
 
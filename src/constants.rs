@@ -7,5 +7,17 @@
 
 pub const SMTPD_NAME: &str = "Arcmail";
 pub const SMTPD_INFO: &str = "ESMTP";
-pub const SMTPD_HELP: &str = "Arcmail SMTP Server\r\n\
-                              Licensed under the GPLv3 or later\r\n";
+pub const LMTPD_INFO: &str = "LMTP";
+pub const QUEUE_PATH: &str = "spool";
+pub const QUEUE_RETRY_INTERVAL: u64 = 300;
+pub const QUEUE_LIFETIME: u64 = 432000;
+pub const LMTP_TIMEOUT: u64 = 300;
+pub const SMTPD_HELP: [&str; 2] = ["Arcmail SMTP Server", "Licensed under the GPLv3 or later"];
+pub const SMTPD_LINE_LIMIT: usize = 512;
+pub const SMTPD_TEXT_LIMIT: usize = 1000;
+pub const SMTPD_AUTH_LINE_LIMIT: usize = 12288;
+pub const SMTPD_DATA_CHUNK: usize = 65536;
+pub const SMTPD_MAX_MESSAGE_SIZE: usize = 10485760;
+pub const SMTPD_MAX_HOLD_TIME: u64 = 604800;
+pub const SMTPD_TLS_RELOAD_INTERVAL: u64 = 60;
+pub const SMTPD_SCRAM_ITERATIONS: u32 = 4096;
@@ -0,0 +1,207 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/date.rs
+// Date and time formats.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// RFC 5322 date in UTC.
+pub fn format_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86400;
+    let rest = secs % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+// asctime(3) date in UTC, as in the mbox From_ line.
+pub fn format_asctime(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86400;
+    let rest = secs % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        year
+    )
+}
+
+// RFC 3339 date-time in UTC, as in the FUTURERELEASE keyword.
+pub fn format_datetime(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0);
+    let rest = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+// RFC 3339 date-time, fractional seconds are dropped.
+pub fn parse_datetime(text: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let part = text.get(range)?;
+        match part.bytes().all(|b| b.is_ascii_digit()) {
+            true => part.parse().ok(),
+            false => None,
+        }
+    };
+    let bytes = text.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    let mut rest = &text[19..];
+    if let Some(frac) = rest.strip_prefix('.') {
+        let end = frac
+            .find(|c: char| !c.is_ascii_digit())
+            .filter(|&end| end > 0)?;
+        rest = &frac[end..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let bytes = rest.as_bytes();
+            if rest.len() != 6 || bytes[3] != b':' {
+                return None;
+            }
+            let digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+            if !digits(&rest[1..3]) || !digits(&rest[4..6]) {
+                return None;
+            }
+            let hours: i64 = rest[1..3].parse().ok()?;
+            let minutes: i64 = rest[4..6].parse().ok()?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: u64 = 1792326896;
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn parses_utc_and_offsets() {
+        assert_eq!(parse_datetime("2026-10-18T12:34:56Z"), at(TIME));
+        assert_eq!(parse_datetime("2026-10-18t12:34:56z"), at(TIME));
+        assert_eq!(parse_datetime("2026-10-18T21:34:56+09:00"), at(TIME));
+        assert_eq!(parse_datetime("2026-10-18T07:04:56-05:30"), at(TIME));
+    }
+
+    #[test]
+    fn drops_fractional_seconds() {
+        assert_eq!(parse_datetime("2026-10-18T12:34:56.789Z"), at(TIME));
+        assert_eq!(parse_datetime("2026-10-18T12:34:56.Z"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_dates() {
+        for text in [
+            "",
+            "2026-10-18T12:34:56",
+            "2026-10-18 12:34:56Z",
+            "2026-13-18T12:34:56Z",
+            "2026-10-00T12:34:56Z",
+            "2026-10-18T24:34:56Z",
+            "2026-10-18T12:34:56+0900",
+            "2026-10-18T12:34:56+09:0a",
+            "+026-10-18T12:34:56Z",
+        ] {
+            assert_eq!(parse_datetime(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn round_trips_formatted_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(TIME);
+        assert_eq!(parse_datetime(&format_datetime(time)), Some(time));
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/delivery/lmtp.rs
+// LMTP client (RFC 2033).
+
+use crate::constants;
+use crate::smtpd::envelope::{SmtpBody, SmtpEnvelope};
+use crate::smtpd::stream::util;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, UnixStream};
+
+// Whether the connection stays on this host.
+pub fn is_local(address: &str) -> bool {
+    match address.strip_prefix("unix:") {
+        Some(_) => true,
+        None => {
+            address
+                .parse::<SocketAddr>()
+                .is_ok_and(|addr| addr.ip().is_loopback())
+                || address
+                    .rsplit_once(':')
+                    .is_some_and(|(host, _)| host.eq_ignore_ascii_case("localhost"))
+        }
+    }
+}
+
+// One transaction for all recipients, returning a reply for each.
+pub async fn deliver(
+    address: &str,
+    host: &str,
+    envelope: &SmtpEnvelope,
+    recipients: &[&str],
+    message: &[u8],
+) -> anyhow::Result<Vec<util::UtilReply>> {
+    let timeout = Duration::from_secs(constants::LMTP_TIMEOUT);
+    let transaction = async {
+        match address.strip_prefix("unix:") {
+            Some(path) => {
+                let stream = UnixStream::connect(path).await?;
+                transaction(stream, host, envelope, recipients, message).await
+            }
+            None => {
+                let stream = TcpStream::connect(address).await?;
+                transaction(stream, host, envelope, recipients, message).await
+            }
+        }
+    };
+    tokio::time::timeout(timeout, transaction)
+        .await
+        .map_err(|_| anyhow::anyhow!("LMTP timeout"))?
+}
+
+struct LmtpClient<S> {
+    reader: BufReader<ReadHalf<S>>,
+    writer: WriteHalf<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite> LmtpClient<S> {
+    async fn command(&mut self, line: &str) -> anyhow::Result<util::UtilReply> {
+        self.send(format!("{}\r\n", line).into_bytes()).await?;
+        self.reply().await
+    }
+
+    async fn send(&mut self, mut data: Vec<u8>) -> anyhow::Result<()> {
+        util::write_flush(&mut self.writer, &mut data).await
+    }
+
+    async fn reply(&mut self) -> anyhow::Result<util::UtilReply> {
+        util::read_reply(&mut self.reader, constants::SMTPD_TEXT_LIMIT).await
+    }
+}
+
+async fn transaction<S>(
+    stream: S,
+    host: &str,
+    envelope: &SmtpEnvelope,
+    recipients: &[&str],
+    message: &[u8],
+) -> anyhow::Result<Vec<util::UtilReply>>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut client = LmtpClient {
+        reader: BufReader::new(reader),
+        writer,
+    };
+    let greeting = client.reply().await?;
+    if greeting.code != 220 {
+        return Err(anyhow::anyhow!("LMTP greeting: {}", greeting.text()));
+    }
+    let lhlo = client.command(&format!("LHLO {}", host)).await?;
+    if lhlo.code != 250 {
+        return Err(anyhow::anyhow!("LMTP LHLO: {}", lhlo.text()));
+    }
+    let extensions: Vec<String> = lhlo
+        .lines
+        .iter()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_ascii_uppercase)
+        .collect();
+    let supports = |name: &str| extensions.iter().any(|ext| ext == name);
+    let chunking = supports("CHUNKING");
+
+    let mut mail = format!("MAIL FROM:<{}>", envelope.reverse_path);
+    match envelope.body {
+        SmtpBody::SevenBit => {}
+        SmtpBody::EightBitMime if supports("8BITMIME") => mail.push_str(" BODY=8BITMIME"),
+        SmtpBody::BinaryMime if chunking && supports("BINARYMIME") => {
+            mail.push_str(" BODY=BINARYMIME")
+        }
+        SmtpBody::EightBitMime | SmtpBody::BinaryMime => {
+            return Ok(rejected(
+                recipients,
+                554,
+                "5.6.3 Body type not supported downstream",
+            ));
+        }
+    }
+    if envelope.smtputf8 {
+        if !supports("SMTPUTF8") {
+            return Ok(rejected(
+                recipients,
+                553,
+                "5.6.7 SMTPUTF8 not supported downstream",
+            ));
+        }
+        mail.push_str(" SMTPUTF8");
+    }
+    let reply = client.command(&mail).await?;
+    if reply.code != 250 {
+        let _ = client.command("QUIT").await;
+        return Ok(recipients.iter().map(|_| reply.clone()).collect());
+    }
+
+    let mut replies: Vec<Option<util::UtilReply>> = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let reply = client.command(&format!("RCPT TO:<{}>", recipient)).await?;
+        replies.push(match reply.code {
+            250 | 251 => None,
+            _ => Some(reply),
+        });
+    }
+    let accepted = replies.iter().filter(|reply| reply.is_none()).count();
+    if accepted == 0 {
+        let _ = client.command("QUIT").await;
+        return Ok(replies.into_iter().flatten().collect());
+    }
+
+    if chunking {
+        client
+            .send(format!("BDAT {} LAST\r\n", message.len()).into_bytes())
+            .await?;
+        client.send(message.to_vec()).await?;
+    } else {
+        let reply = client.command("DATA").await?;
+        if reply.code != 354 {
+            let _ = client.command("QUIT").await;
+            return Ok(replies
+                .into_iter()
+                .map(|status| status.unwrap_or_else(|| reply.clone()))
+                .collect());
+        }
+        client.send(dot_stuff(message)).await?;
+    }
+    // RFC 2033 4.2: one reply per accepted recipient, in RCPT order.
+    let mut out = Vec::with_capacity(replies.len());
+    for reply in replies {
+        out.push(match reply {
+            Some(reply) => reply,
+            None => client.reply().await?,
+        });
+    }
+    let _ = client.command("QUIT").await;
+    Ok(out)
+}
+
+fn rejected(recipients: &[&str], code: u16, text: &str) -> Vec<util::UtilReply> {
+    recipients
+        .iter()
+        .map(|_| util::UtilReply {
+            code,
+            lines: vec![text.to_string()],
+        })
+        .collect()
+}
+
+// CRLF line ends, leading dots doubled, ended by "." on its own line.
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(message.len() + 5);
+    let message = message.strip_suffix(b"\n").unwrap_or(message);
+    let message = message.strip_suffix(b"\r").unwrap_or(message);
+    if !message.is_empty() {
+        for line in message.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.starts_with(b".") {
+                data.push(b'.');
+            }
+            data.extend_from_slice(line);
+            data.extend_from_slice(b"\r\n");
+        }
+    }
+    data.extend_from_slice(b".\r\n");
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leading_dots_are_doubled() {
+        let data = dot_stuff(b"Subject: test\r\n\r\n.hidden\r\n..two\r\nend.\r\n");
+        assert_eq!(
+            data,
+            b"Subject: test\r\n\r\n..hidden\r\n...two\r\nend.\r\n.\r\n"
+        );
+    }
+
+    #[test]
+    fn bare_dot_line_is_not_the_end() {
+        let data = dot_stuff(b"one\r\n.\r\ntwo\r\n");
+        assert_eq!(data, b"one\r\n..\r\ntwo\r\n.\r\n");
+    }
+
+    #[test]
+    fn line_ends_become_crlf() {
+        assert_eq!(dot_stuff(b"one\ntwo"), b"one\r\ntwo\r\n.\r\n");
+    }
+
+    #[test]
+    fn empty_message_is_only_the_end() {
+        assert_eq!(dot_stuff(b""), b".\r\n");
+    }
+}
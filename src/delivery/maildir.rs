@@ -0,0 +1,72 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/delivery/maildir.rs
+// Maildir++ mailboxes.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Folder of a mailbox, INBOX when folder is None. Subfolders follow
+// Maildir++ and are only used when they already exist.
+pub fn folder(mailbox: &Path, folder: Option<&str>) -> PathBuf {
+    match folder {
+        Some(name) if valid_folder(name) => {
+            let path = mailbox.join(format!(".{}", name));
+            match path.is_dir() {
+                true => path,
+                false => mailbox.to_path_buf(),
+            }
+        }
+        _ => mailbox.to_path_buf(),
+    }
+}
+
+fn valid_folder(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+// Write into tmp/, sync, then move into new/.
+pub fn deliver(folder: &Path, host: &str, message: &[u8]) -> anyhow::Result<PathBuf> {
+    for dir in ["tmp", "new", "cur"] {
+        fs::create_dir_all(folder.join(dir))?;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let name = format!(
+        "{}.M{}P{}Q{}.{},S={}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        host.replace('/', "\\057").replace(':', "\\072"),
+        message.len()
+    );
+    let tmp = folder.join("tmp").join(&name);
+    let new = folder.join("new").join(&name);
+    let mut file = File::create_new(&tmp)?;
+    if let Err(e) = file.write_all(message).and_then(|_| file.sync_all()) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    if let Err(e) = fs::hard_link(&tmp, &new) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    fs::remove_file(&tmp)?;
+    File::open(folder.join("new"))?.sync_all()?;
+    Ok(new)
+}
@@ -0,0 +1,180 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/delivery/mbox.rs
+// mboxrd mailboxes.
+
+use crate::date;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const LOCK_RETRIES: u32 = 10;
+const LOCK_INTERVAL: Duration = Duration::from_secs(1);
+const DOTLOCK_STALE: Duration = Duration::from_secs(300);
+
+// "<mbox>.lock", removed on drop.
+struct DotLock(PathBuf);
+
+impl DotLock {
+    fn acquire(path: &Path) -> io::Result<Self> {
+        let mut lock = path.as_os_str().to_owned();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        for _ in 0..LOCK_RETRIES {
+            match File::create_new(&lock) {
+                Ok(_) => return Ok(DotLock(lock)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let stale = fs::metadata(&lock)
+                        .and_then(|meta| meta.modified())
+                        .ok()
+                        .and_then(|time| time.elapsed().ok())
+                        .is_some_and(|age| age > DOTLOCK_STALE);
+                    if stale {
+                        let _ = fs::remove_file(&lock);
+                        continue;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            std::thread::sleep(LOCK_INTERVAL);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is locked", path.display()),
+        ))
+    }
+}
+
+impl Drop for DotLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Write lock on the whole file, released when the file is closed.
+fn fcntl_lock(file: &File) -> io::Result<()> {
+    // SAFETY: flock is plain data and all-zero is a valid value.
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    for _ in 0..LOCK_RETRIES {
+        // SAFETY: the descriptor is open and lock outlives the call.
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if !matches!(e.raw_os_error(), Some(libc::EACCES | libc::EAGAIN)) {
+            return Err(e);
+        }
+        std::thread::sleep(LOCK_INTERVAL);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::WouldBlock,
+        "mailbox is locked by another process",
+    ))
+}
+
+// Append a message, truncating back to the old size if anything fails.
+pub fn deliver(path: &Path, sender: &str, message: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let _dotlock = DotLock::acquire(path)?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    fcntl_lock(&file)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut data = Vec::new();
+    // Keep the empty line other writers may have left out.
+    if len > 0 {
+        let mut tail = [0u8; 2];
+        let start = len.saturating_sub(2);
+        file.seek(SeekFrom::Start(start))?;
+        let tail = &mut tail[..(len - start) as usize];
+        file.read_exact(tail)?;
+        file.seek(SeekFrom::End(0))?;
+        if !tail.ends_with(b"\n\n") {
+            data.extend_from_slice(if tail.ends_with(b"\n") {
+                b"\n"
+            } else {
+                b"\n\n"
+            });
+        }
+    }
+    data.extend_from_slice(&format(sender, message));
+    if let Err(e) = file.write_all(&data).and_then(|_| file.sync_all()) {
+        let _ = file.set_len(len).and_then(|_| file.sync_all());
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// From_ line, the message with LF line ends and ">From " quoted as
+// in mboxrd, then an empty line.
+fn format(sender: &str, message: &[u8]) -> Vec<u8> {
+    let sender = match sender {
+        "" => "MAILER-DAEMON",
+        sender => sender,
+    };
+    let mut data = format!(
+        "From {} {}\n",
+        sender,
+        date::format_asctime(SystemTime::now())
+    )
+    .into_bytes();
+    let message = message.strip_suffix(b"\n").unwrap_or(message);
+    for line in message.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let quoted = line.iter().position(|&b| b != b'>').unwrap_or(line.len());
+        if line[quoted..].starts_with(b"From ") {
+            data.push(b'>');
+        }
+        data.extend_from_slice(line);
+        data.push(b'\n');
+    }
+    data.push(b'\n');
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The message part after the From_ line.
+    fn body(data: &[u8]) -> &[u8] {
+        let start = data.iter().position(|&b| b == b'\n').unwrap() + 1;
+        &data[start..]
+    }
+
+    #[test]
+    fn from_line_uses_mailer_daemon_for_null_sender() {
+        let data = format("", b"Subject: test\r\n");
+        assert!(data.starts_with(b"From MAILER-DAEMON "));
+        let data = format("user@example.com", b"Subject: test\r\n");
+        assert!(data.starts_with(b"From user@example.com "));
+    }
+
+    #[test]
+    fn lines_end_with_lf_and_message_with_empty_line() {
+        let data = format("user@example.com", b"Subject: test\r\n\r\nbody\r\n");
+        assert_eq!(body(&data), b"Subject: test\n\nbody\n\n");
+    }
+
+    #[test]
+    fn from_lines_are_quoted() {
+        let message = b"\r\nFrom here\r\n>From there\r\n>>From afar\r\nFromage\r\n";
+        let data = format("user@example.com", message);
+        assert_eq!(
+            body(&data),
+            b"\n>From here\n>>From there\n>>>From afar\nFromage\n\n"
+        );
+    }
+}
@@ -0,0 +1,620 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/delivery/mod.rs
+// Delivery of queued messages.
+
+pub mod lmtp;
+pub mod maildir;
+pub mod mbox;
+
+use crate::smtpd::envelope::{DeliverByMode, SmtpBody, SmtpEnvelope, SmtpRecipient, TlsPolicy};
+use crate::{conf, constants, directory, dsn, queue};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const MAX_ALIAS_DEPTH: usize = 8;
+
+struct LocalDomain {
+    config: Arc<conf::ConfigSmtpServer>,
+    directory: Arc<directory::Directory>,
+}
+
+enum Target {
+    Local {
+        domain: String,
+        mailbox: String,
+        folder: Option<String>,
+    },
+    Remote(String),
+    Unknown(String),
+}
+
+impl Target {
+    fn address(&self) -> &str {
+        match self {
+            Target::Local { mailbox, .. } => mailbox,
+            Target::Remote(address) | Target::Unknown(address) => address,
+        }
+    }
+}
+
+pub enum Outcome {
+    Delivered,
+    Deferred(String, String),
+    Failed(String, String),
+}
+
+// Entry state after an attempt, None when nothing is left to deliver,
+// and the notification to queue for the sender.
+type Attempt = (Option<queue::QueueEntry>, Option<(SmtpEnvelope, Vec<u8>)>);
+
+pub struct DeliveryAgent {
+    queue: Arc<queue::Queue>,
+    domains: HashMap<String, LocalDomain>,
+    host: String,
+}
+
+impl DeliveryAgent {
+    pub fn new(queue: Arc<queue::Queue>) -> Self {
+        DeliveryAgent {
+            queue,
+            domains: HashMap::new(),
+            host: String::new(),
+        }
+    }
+
+    // Servers with a delivery config receive mail for their domain.
+    pub fn add(
+        &mut self,
+        config: Arc<conf::ConfigSmtpServer>,
+        directory: Arc<directory::Directory>,
+    ) {
+        if self.host.is_empty() {
+            self.host = config.domain.clone();
+        }
+        if config.delivery.is_some() {
+            self.domains.insert(
+                config.domain.to_lowercase(),
+                LocalDomain { config, directory },
+            );
+        }
+    }
+
+    pub fn run(self: Arc<Self>) {
+        tokio::spawn(async move { self.run_loop().await });
+    }
+
+    // Deliver while an LMTP client waits, one outcome per recipient.
+    // A recipient expanding to several targets takes the worst of them.
+    pub async fn deliver_now(
+        self: &Arc<Self>,
+        envelope: &SmtpEnvelope,
+        body: Vec<u8>,
+    ) -> Vec<Outcome> {
+        let targets = self.targets(envelope);
+        let outcomes = self.deliver(envelope, &Arc::new(body), &targets).await;
+        let mut results: Vec<Option<Outcome>> =
+            envelope.forward_paths.iter().map(|_| None).collect();
+        for ((index, _, _), outcome) in targets.iter().zip(outcomes) {
+            let result = &mut results[*index];
+            *result = Some(match result.take() {
+                Some(previous) => worse(previous, outcome),
+                None => outcome,
+            });
+        }
+        results
+            .into_iter()
+            .map(|outcome| {
+                outcome.unwrap_or_else(|| {
+                    Outcome::Failed("5.1.1".to_string(), "Mailbox unavailable".to_string())
+                })
+            })
+            .collect()
+    }
+
+    async fn run_loop(self: Arc<Self>) {
+        loop {
+            let now = SystemTime::now();
+            let mut due: Vec<queue::QueueEntry> = self
+                .queue
+                .entries()
+                .into_iter()
+                .filter(|entry| ready_at(entry).is_none_or(|time| time <= now))
+                .collect();
+            due.sort_by(|a, b| {
+                b.envelope
+                    .priority
+                    .cmp(&a.envelope.priority)
+                    .then(a.received.cmp(&b.received))
+            });
+            for entry in due {
+                let id = entry.id.clone();
+                let result = match self.attempt(entry).await {
+                    Ok(attempt) => self.finish(&id, attempt).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    eprintln!("{}: delivery failed: {}", id, e);
+                }
+            }
+            let now = SystemTime::now();
+            let retry = Duration::from_secs(constants::QUEUE_RETRY_INTERVAL);
+            let wait = self
+                .queue
+                .entries()
+                .iter()
+                .filter_map(ready_at)
+                .map(|time| time.duration_since(now).unwrap_or_default())
+                .fold(retry, Duration::min);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.queue.notified() => {}
+            }
+        }
+    }
+
+    // The report is queued before the entry changes, so a crash can
+    // duplicate a notification but not lose one.
+    async fn finish(&self, id: &str, (entry, report): Attempt) -> anyhow::Result<()> {
+        if let Some((envelope, body)) = report {
+            self.queue.push(envelope, body).await?;
+        }
+        match entry {
+            Some(entry) => self.queue.update(entry).await,
+            None => self.queue.remove(id).await,
+        }
+    }
+
+    async fn attempt(self: &Arc<Self>, mut entry: queue::QueueEntry) -> anyhow::Result<Attempt> {
+        let body = Arc::new(self.queue.body(&entry.id).await?);
+        let now = SystemTime::now();
+        let lifetime = Duration::from_secs(constants::QUEUE_LIFETIME);
+        let expired =
+            now >= entry.received + lifetime
+                || entry.envelope.deliver_by.as_ref().is_some_and(|by| {
+                    matches!(by.mode, DeliverByMode::Return) && now >= by.deadline
+                });
+        let overdue = notice_at(&entry).is_some_and(|deadline| now >= deadline);
+        let targets = self.targets(&entry.envelope);
+        let outcomes = self.deliver(&entry.envelope, &body, &targets).await;
+        let mut pending = Vec::new();
+        let mut delayed = Vec::new();
+        let mut done = Vec::new();
+        for ((_, rcpt, _), outcome) in targets.into_iter().zip(outcomes) {
+            match outcome {
+                Outcome::Delivered => {
+                    done.push((rcpt, dsn::DsnAction::Delivered, "2.0.0".to_string(), None));
+                }
+                Outcome::Failed(status, text) => {
+                    done.push((rcpt, dsn::DsnAction::Failed, status, Some(text)));
+                }
+                Outcome::Deferred(_, text) if expired => {
+                    let text = format!("Delivery time expired, {}", text);
+                    done.push((
+                        rcpt,
+                        dsn::DsnAction::Failed,
+                        "5.4.7".to_string(),
+                        Some(text),
+                    ));
+                }
+                // RFC 2852 4.1.4.2: a passed ";N" deadline is reported once,
+                // NOTIFY=DELAY only hears about the first deferral. Later
+                // attempts only retry recipients deferred by that one.
+                Outcome::Deferred(_, text) => {
+                    if overdue {
+                        let text = format!("Delivery time expired, {}", text);
+                        delayed.push((rcpt.clone(), "4.4.7".to_string(), text));
+                    } else if !entry.delay_notified
+                        && rcpt.notify.as_ref().is_some_and(|notify| notify.delay)
+                    {
+                        delayed.push((rcpt.clone(), "4.0.0".to_string(), text));
+                    }
+                    pending.push(rcpt);
+                }
+            }
+        }
+        for (rcpt, status, text) in delayed {
+            done.push((rcpt, dsn::DsnAction::Delayed, status, Some(text)));
+        }
+        let statuses: Vec<dsn::DsnStatus> = done
+            .iter()
+            .map(|(recipient, action, status, text)| dsn::DsnStatus {
+                recipient,
+                action: *action,
+                status: status.clone(),
+                diagnostic: text.clone(),
+            })
+            .collect();
+        let report = dsn::report(&self.host, &entry.envelope, &statuses, &body)
+            .map(|data| (notification(&entry.envelope), data));
+        if pending.is_empty() {
+            return Ok((None, report));
+        }
+        entry.envelope.forward_paths = pending;
+        entry.attempts += 1;
+        entry.deliver_by_notified |= overdue;
+        entry.delay_notified = true;
+        entry.retry_at = Some(now + Duration::from_secs(constants::QUEUE_RETRY_INTERVAL));
+        Ok((Some(entry), report))
+    }
+
+    // Resolve every recipient, keeping the index of the one it came from.
+    fn targets(&self, envelope: &SmtpEnvelope) -> Vec<(usize, SmtpRecipient, Target)> {
+        let mut targets = Vec::new();
+        for (index, recipient) in envelope.forward_paths.iter().enumerate() {
+            let mut resolved = Vec::new();
+            self.resolve(&recipient.path, 0, &mut resolved);
+            for target in resolved {
+                let mut rcpt = recipient.clone();
+                if !target.address().eq_ignore_ascii_case(&recipient.path) {
+                    rcpt.path = target.address().to_string();
+                    rcpt.orcpt
+                        .get_or_insert_with(|| ("rfc822".to_string(), recipient.path.clone()));
+                }
+                targets.push((index, rcpt, target));
+            }
+        }
+        targets
+    }
+
+    // One outcome per target. LMTP domains get a single transaction,
+    // mailbox files are written off the async threads.
+    async fn deliver(
+        self: &Arc<Self>,
+        envelope: &SmtpEnvelope,
+        body: &Arc<Vec<u8>>,
+        targets: &[(usize, SmtpRecipient, Target)],
+    ) -> Vec<Outcome> {
+        let mut outcomes: Vec<Option<Outcome>> = targets.iter().map(|_| None).collect();
+        let mut lmtp: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut files = Vec::new();
+        for (i, (_, _, target)) in targets.iter().enumerate() {
+            match target {
+                Target::Local { domain, .. } if self.lmtp(domain).is_some() => {
+                    lmtp.entry(domain.as_str()).or_default().push(i);
+                }
+                Target::Local {
+                    domain,
+                    mailbox,
+                    folder,
+                } => files.push((i, domain.clone(), mailbox.clone(), folder.clone())),
+                // RFC 8689: there is no verified TLS hop to remote domains,
+                // so REQUIRETLS messages fail instead of waiting.
+                Target::Remote(_) if !envelope.tls.permits(false, false) => {
+                    outcomes[i] = Some(tls_required());
+                }
+                Target::Remote(_) => {
+                    outcomes[i] = Some(Outcome::Deferred(
+                        "4.4.0".to_string(),
+                        "No route to remote domain".to_string(),
+                    ));
+                }
+                Target::Unknown(_) => {
+                    outcomes[i] = Some(Outcome::Failed(
+                        "5.1.1".to_string(),
+                        "Mailbox unavailable".to_string(),
+                    ));
+                }
+            }
+        }
+        for (domain, indices) in lmtp {
+            let Some(address) = self.lmtp(domain) else {
+                continue;
+            };
+            // RFC 8689: no cleartext network hop for REQUIRETLS messages.
+            if !lmtp::is_local(address) && !envelope.tls.permits(false, false) {
+                for i in indices {
+                    outcomes[i] = Some(tls_required());
+                }
+                continue;
+            }
+            let recipients: Vec<&str> = indices
+                .iter()
+                .map(|&i| targets[i].1.path.as_str())
+                .collect();
+            match lmtp::deliver(address, &self.host, envelope, &recipients, body).await {
+                Ok(replies) => {
+                    for (i, reply) in indices.into_iter().zip(replies) {
+                        outcomes[i] = Some(match reply.code {
+                            200..=299 => Outcome::Delivered,
+                            400..=499 => Outcome::Deferred(reply.status(), reply.text()),
+                            _ => Outcome::Failed(reply.status(), reply.text()),
+                        });
+                    }
+                }
+                Err(e) => {
+                    for i in indices {
+                        outcomes[i] = Some(Outcome::Deferred("4.4.1".to_string(), e.to_string()));
+                    }
+                }
+            }
+        }
+        if !files.is_empty() {
+            let agent = self.clone();
+            let envelope = envelope.clone();
+            let body = body.clone();
+            let indices: Vec<usize> = files.iter().map(|file| file.0).collect();
+            let written = tokio::task::spawn_blocking(move || {
+                files
+                    .into_iter()
+                    .map(|(i, domain, mailbox, folder)| {
+                        let outcome = agent.deliver_local(
+                            &domain,
+                            &mailbox,
+                            folder.as_deref(),
+                            &envelope,
+                            &body,
+                        );
+                        (i, outcome)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            match written {
+                Ok(written) => {
+                    for (i, outcome) in written {
+                        outcomes[i] = Some(outcome);
+                    }
+                }
+                Err(e) => {
+                    for i in indices {
+                        outcomes[i] = Some(Outcome::Deferred("4.3.0".to_string(), e.to_string()));
+                    }
+                }
+            }
+        }
+        outcomes
+            .into_iter()
+            .map(|outcome| {
+                outcome.unwrap_or(Outcome::Deferred(
+                    "4.3.0".to_string(),
+                    "Not attempted".to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    fn lmtp(&self, domain: &str) -> Option<&str> {
+        self.domains
+            .get(domain)
+            .and_then(|local| local.config.delivery.as_ref())
+            .and_then(|delivery| delivery.lmtp.as_deref())
+    }
+
+    // Expand aliases down to mailboxes, "user+folder" picks a subfolder.
+    fn resolve(&self, address: &str, depth: usize, out: &mut Vec<Target>) {
+        let Some((local, domain)) = address.rsplit_once('@') else {
+            out.push(Target::Unknown(address.to_string()));
+            return;
+        };
+        let key = domain.to_lowercase();
+        let Some(local_domain) = self.domains.get(&key) else {
+            out.push(Target::Remote(address.to_string()));
+            return;
+        };
+        if let Some(members) = local_domain.directory.expand(address) {
+            for member in members {
+                match depth < MAX_ALIAS_DEPTH {
+                    true => self.resolve(&member, depth + 1, out),
+                    false => out.push(Target::Unknown(member)),
+                }
+            }
+            return;
+        }
+        let (user, folder) = match local.split_once('+') {
+            Some((user, folder)) => (user, Some(folder.to_string())),
+            None => (local, None),
+        };
+        let base = format!("{}@{}", user, domain);
+        match local_domain.directory.mailbox(address) {
+            Some(mailbox) => out.push(Target::Local {
+                domain: key,
+                mailbox: mailbox.address.clone(),
+                folder: None,
+            }),
+            None => match local_domain.directory.mailbox(&base) {
+                Some(mailbox) => out.push(Target::Local {
+                    domain: key,
+                    mailbox: mailbox.address.clone(),
+                    folder,
+                }),
+                None if self.lmtp(&key).is_some() => out.push(Target::Local {
+                    domain: key,
+                    mailbox: address.to_string(),
+                    folder: None,
+                }),
+                None => out.push(Target::Unknown(address.to_string())),
+            },
+        }
+    }
+
+    fn deliver_local(
+        &self,
+        domain: &str,
+        mailbox: &str,
+        folder: Option<&str>,
+        envelope: &SmtpEnvelope,
+        body: &[u8],
+    ) -> Outcome {
+        let Some(local_domain) = self.domains.get(domain) else {
+            return Outcome::Failed("5.1.2".to_string(), "Domain not local".to_string());
+        };
+        let Some(config) = &local_domain.config.delivery else {
+            return Outcome::Failed("5.1.2".to_string(), "Domain not local".to_string());
+        };
+        let user = mailbox
+            .rsplit_once('@')
+            .map_or(mailbox, |(user, _)| user)
+            .to_lowercase();
+        if user.is_empty() || user.starts_with('.') || user.contains(['/', '\0']) {
+            return Outcome::Failed("5.1.3".to_string(), "Mailbox name not allowed".to_string());
+        }
+        let root = PathBuf::from(&config.mail_root).join(user);
+        let mut message = format!(
+            "Return-Path: <{}>\r\nDelivered-To: {}\r\n",
+            envelope.reverse_path, mailbox
+        )
+        .into_bytes();
+        message.extend_from_slice(body);
+        let result = match config.format {
+            conf::ConfigMailboxFormat::Maildir => maildir::deliver(
+                &maildir::folder(&root, folder),
+                &local_domain.config.domain,
+                &message,
+            )
+            .map(|_| ()),
+            conf::ConfigMailboxFormat::Mbox => {
+                mbox::deliver(&root, &envelope.reverse_path, &message)
+            }
+        };
+        match result {
+            Ok(()) => Outcome::Delivered,
+            Err(e) => Outcome::Deferred("4.3.0".to_string(), e.to_string()),
+        }
+    }
+}
+
+// Deferred before failed before delivered.
+fn worse(a: Outcome, b: Outcome) -> Outcome {
+    match (&a, &b) {
+        (Outcome::Deferred(..), _) => a,
+        (_, Outcome::Deferred(..)) => b,
+        (Outcome::Failed(..), _) => a,
+        _ => b,
+    }
+}
+
+fn tls_required() -> Outcome {
+    Outcome::Failed(
+        "5.7.10".to_string(),
+        "REQUIRETLS support required".to_string(),
+    )
+}
+
+// A pending ";N" notice does not wait for the next retry.
+fn ready_at(entry: &queue::QueueEntry) -> Option<SystemTime> {
+    let ready = match (entry.envelope.hold_until, entry.retry_at) {
+        (Some(hold), Some(retry)) => Some(hold.max(retry)),
+        (hold, retry) => hold.or(retry),
+    };
+    match (ready, notice_at(entry)) {
+        (Some(ready), Some(deadline)) => Some(ready.min(deadline)),
+        (ready, _) => ready,
+    }
+}
+
+// Deadline of a DELIVERBY ";N" notice still to be sent.
+fn notice_at(entry: &queue::QueueEntry) -> Option<SystemTime> {
+    entry
+        .envelope
+        .deliver_by
+        .as_ref()
+        .filter(|by| matches!(by.mode, DeliverByMode::Notify) && !entry.deliver_by_notified)
+        .map(|by| by.deadline)
+}
+
+// Envelope of a delivery status notification back to the sender.
+fn notification(envelope: &SmtpEnvelope) -> SmtpEnvelope {
+    let mut report = SmtpEnvelope::new(String::new());
+    report
+        .forward_paths
+        .push(SmtpRecipient::new(envelope.reverse_path.clone()));
+    report.smtputf8 = envelope.smtputf8;
+    if !matches!(envelope.body, SmtpBody::SevenBit) {
+        report.body = SmtpBody::EightBitMime;
+    }
+    // RFC 8689 5: reports on REQUIRETLS messages keep the requirement.
+    if matches!(envelope.tls, TlsPolicy::Required) {
+        report.tls = TlsPolicy::Required;
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtpd::envelope::{DeliverBy, DsnNotify};
+
+    fn open_queue(name: &str) -> (PathBuf, Arc<queue::Queue>) {
+        let path = std::env::temp_dir().join(format!("arcmail-{}-{}", name, std::process::id()));
+        let config = conf::ConfigQueue {
+            path: path.to_string_lossy().into_owned(),
+        };
+        (path, Arc::new(queue::Queue::open(&config).unwrap()))
+    }
+
+    async fn push(queue: &queue::Queue, envelope: SmtpEnvelope) -> String {
+        let body = b"Subject: test\r\n\r\nbody\r\n".to_vec();
+        queue.push(envelope, body).await.unwrap()
+    }
+
+    // Run one attempt on the entry and return the reports it queued.
+    async fn attempt(queue: &Arc<queue::Queue>, id: &str) -> Vec<String> {
+        let agent = Arc::new(DeliveryAgent::new(queue.clone()));
+        let before: Vec<String> = queue.entries().into_iter().map(|entry| entry.id).collect();
+        let entry = queue
+            .entries()
+            .into_iter()
+            .find(|entry| entry.id == id)
+            .unwrap();
+        let result = agent.attempt(entry).await.unwrap();
+        agent.finish(id, result).await.unwrap();
+        let mut reports = Vec::new();
+        for entry in queue.entries() {
+            if !before.contains(&entry.id) {
+                assert!(entry.envelope.reverse_path.is_empty());
+                let body = queue.body(&entry.id).await.unwrap();
+                reports.push(String::from_utf8(body).unwrap());
+            }
+        }
+        reports
+    }
+
+    #[tokio::test]
+    async fn deferred_recipient_with_notify_delay_is_reported_once() {
+        let (path, queue) = open_queue("delay");
+        let mut envelope = SmtpEnvelope::new("sender@example.org".to_string());
+        let mut recipient = SmtpRecipient::new("user@remote.example".to_string());
+        recipient.notify = Some(DsnNotify {
+            success: false,
+            failure: true,
+            delay: true,
+        });
+        envelope.forward_paths.push(recipient);
+        let id = push(&queue, envelope).await;
+
+        let reports = attempt(&queue, &id).await;
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].contains("Action: delayed"));
+        assert!(reports[0].contains("Status: 4.0.0"));
+        assert!(queue.entries().iter().any(|entry| entry.id == id));
+        assert!(attempt(&queue, &id).await.is_empty());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn passed_deliver_by_notify_deadline_is_reported_once() {
+        let (path, queue) = open_queue("deliverby");
+        let mut envelope = SmtpEnvelope::new("sender@example.org".to_string());
+        envelope
+            .forward_paths
+            .push(SmtpRecipient::new("user@remote.example".to_string()));
+        envelope.deliver_by = Some(DeliverBy {
+            deadline: SystemTime::now() - Duration::from_secs(1),
+            mode: DeliverByMode::Notify,
+            trace: false,
+        });
+        let id = push(&queue, envelope).await;
+
+        let reports = attempt(&queue, &id).await;
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].contains("Action: delayed"));
+        assert!(reports[0].contains("Status: 4.4.7"));
+        assert!(attempt(&queue, &id).await.is_empty());
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
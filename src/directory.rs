@@ -0,0 +1,137 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/directory.rs
+// Local recipients and aliases.
+
+use crate::conf;
+use std::collections::{HashMap, HashSet};
+
+pub struct Mailbox {
+    pub address: String,
+    pub name: Option<String>,
+}
+
+impl Mailbox {
+    // "Full Name <local@domain>" as used in VRFY and EXPN replies.
+    pub fn display(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} <{}>", name, self.address),
+            None => format!("<{}>", self.address),
+        }
+    }
+}
+
+pub enum DirectoryEntry<'a> {
+    Mailbox(&'a Mailbox),
+    Alias(&'a str),
+}
+
+pub struct Directory {
+    mailboxes: HashMap<String, Mailbox>,
+    aliases: HashMap<String, (String, Vec<String>)>,
+}
+
+impl Directory {
+    pub fn new(config: &conf::ConfigDirectory) -> Self {
+        let mailboxes = config
+            .mailboxes
+            .iter()
+            .map(|mailbox| {
+                (
+                    mailbox.address.to_lowercase(),
+                    Mailbox {
+                        address: mailbox.address.clone(),
+                        name: mailbox.name.clone(),
+                    },
+                )
+            })
+            .collect();
+        let aliases = config
+            .aliases
+            .iter()
+            .map(|alias| {
+                (
+                    alias.address.to_lowercase(),
+                    (alias.address.clone(), alias.members.clone()),
+                )
+            })
+            .collect();
+        Directory { mailboxes, aliases }
+    }
+
+    pub fn mailbox(&self, address: &str) -> Option<&Mailbox> {
+        self.mailboxes.get(&address.to_lowercase())
+    }
+
+    // Whether mail to the address has somewhere to go, "user+folder"
+    // included.
+    pub fn accepts(&self, address: &str) -> bool {
+        let key = address.to_lowercase();
+        if self.mailboxes.contains_key(&key) || self.aliases.contains_key(&key) {
+            return true;
+        }
+        match key.rsplit_once('@') {
+            Some((local, domain)) => local
+                .split_once('+')
+                .is_some_and(|(user, _)| self.mailbox(&format!("{}@{}", user, domain)).is_some()),
+            None => false,
+        }
+    }
+
+    // Entries matching a full address, a local part, or part of a name.
+    pub fn search(&self, query: &str) -> Vec<DirectoryEntry<'_>> {
+        let query = query.trim();
+        let query = query
+            .strip_prefix('<')
+            .and_then(|query| query.strip_suffix('>'))
+            .unwrap_or(query)
+            .to_lowercase();
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let local = |key: &str| key.rsplit_once('@').map_or(key, |(local, _)| local) == query;
+        let mut found: Vec<DirectoryEntry> = Vec::new();
+        for (key, mailbox) in &self.mailboxes {
+            let by_name = !query.contains('@')
+                && mailbox
+                    .name
+                    .as_ref()
+                    .is_some_and(|name| name.to_lowercase().contains(&query));
+            if *key == query || (!query.contains('@') && local(key)) || by_name {
+                found.push(DirectoryEntry::Mailbox(mailbox));
+            }
+        }
+        for (key, (address, _)) in &self.aliases {
+            if *key == query || (!query.contains('@') && local(key)) {
+                found.push(DirectoryEntry::Alias(address));
+            }
+        }
+        found
+    }
+
+    // Expand an alias into its final recipients, nested aliases included.
+    pub fn expand(&self, address: &str) -> Option<Vec<String>> {
+        self.aliases.get(&address.to_lowercase())?;
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        self.expand_into(address, &mut seen, &mut out);
+        Some(out)
+    }
+
+    fn expand_into(&self, address: &str, seen: &mut HashSet<String>, out: &mut Vec<String>) {
+        let key = address.to_lowercase();
+        if !seen.insert(key.clone()) {
+            return;
+        }
+        match self.aliases.get(&key) {
+            Some((_, members)) => {
+                for member in members {
+                    self.expand_into(member, seen, out);
+                }
+            }
+            None => out.push(address.to_string()),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/dsn.rs
+// Delivery Status Notification (RFC 3461, RFC 3464).
+
+use crate::smtpd::envelope::{DsnRet, SmtpEnvelope, SmtpRecipient};
+use crate::{constants, date};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy)]
+pub enum DsnAction {
+    Failed,
+    Delayed,
+    Delivered,
+}
+
+pub struct DsnStatus<'a> {
+    pub recipient: &'a SmtpRecipient,
+    pub action: DsnAction,
+    pub status: String,
+    pub diagnostic: Option<String>,
+}
+
+impl DsnAction {
+    fn name(&self) -> &'static str {
+        match self {
+            DsnAction::Failed => "failed",
+            DsnAction::Delayed => "delayed",
+            DsnAction::Delivered => "delivered",
+        }
+    }
+
+    // Without NOTIFY, failures and delays are reported but successes are not.
+    fn wanted(&self, recipient: &SmtpRecipient) -> bool {
+        match (&recipient.notify, self) {
+            (Some(notify), DsnAction::Failed) => notify.failure,
+            (Some(notify), DsnAction::Delayed) => notify.delay,
+            (Some(notify), DsnAction::Delivered) => notify.success,
+            (None, DsnAction::Delivered) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+// Build the notification sent back to the reverse-path, if any is due.
+pub fn report(
+    domain: &str,
+    envelope: &SmtpEnvelope,
+    statuses: &[DsnStatus],
+    message: &[u8],
+) -> Option<Vec<u8>> {
+    if envelope.reverse_path.is_empty() {
+        return None;
+    }
+    let statuses: Vec<&DsnStatus> = statuses
+        .iter()
+        .filter(|status| status.action.wanted(status.recipient))
+        .collect();
+    if statuses.is_empty() {
+        return None;
+    }
+    let subject = if statuses
+        .iter()
+        .any(|status| matches!(status.action, DsnAction::Failed))
+    {
+        "Failure"
+    } else if statuses
+        .iter()
+        .any(|status| matches!(status.action, DsnAction::Delayed))
+    {
+        "Delay"
+    } else {
+        "Success"
+    };
+    let now = SystemTime::now();
+    let stamp = now
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or(0);
+    let boundary = format!("{}.{}/{}", stamp, std::process::id(), domain);
+
+    let mut text = String::new();
+    text.push_str(&format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{}>\r\n",
+        domain
+    ));
+    text.push_str(&format!("To: <{}>\r\n", envelope.reverse_path));
+    text.push_str(&format!(
+        "Subject: Delivery Status Notification ({})\r\n",
+        subject
+    ));
+    text.push_str(&format!("Date: {}\r\n", date::format_date(now)));
+    text.push_str(&format!(
+        "Message-ID: <{}.{}@{}>\r\n",
+        stamp,
+        std::process::id(),
+        domain
+    ));
+    text.push_str("Auto-Submitted: auto-replied\r\n");
+    text.push_str("MIME-Version: 1.0\r\n");
+    text.push_str(&format!(
+        "Content-Type: multipart/report; report-type=delivery-status;\r\n\tboundary=\"{}\"\r\n\r\n",
+        boundary
+    ));
+
+    text.push_str(&format!("--{}\r\n", boundary));
+    text.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+    text.push_str(&format!(
+        "This is the mail system at host {} ({}).\r\n\r\n",
+        domain,
+        constants::SMTPD_NAME
+    ));
+    for status in &statuses {
+        text.push_str(&format!(
+            "<{}>: {}",
+            status.recipient.path,
+            status.action.name()
+        ));
+        if let Some(diagnostic) = &status.diagnostic {
+            text.push_str(&format!(", {}", diagnostic));
+        }
+        text.push_str("\r\n");
+    }
+    text.push_str("\r\n");
+
+    text.push_str(&format!("--{}\r\n", boundary));
+    text.push_str("Content-Type: message/delivery-status\r\n\r\n");
+    text.push_str(&format!("Reporting-MTA: dns; {}\r\n", domain));
+    if let Some(envid) = &envelope.envid {
+        text.push_str(&format!("Original-Envelope-Id: {}\r\n", envid));
+    }
+    for status in &statuses {
+        text.push_str("\r\n");
+        if let Some((kind, address)) = &status.recipient.orcpt {
+            text.push_str(&format!("Original-Recipient: {};{}\r\n", kind, address));
+        }
+        text.push_str(&format!(
+            "Final-Recipient: rfc822; {}\r\n",
+            status.recipient.path
+        ));
+        text.push_str(&format!("Action: {}\r\n", status.action.name()));
+        text.push_str(&format!("Status: {}\r\n", status.status));
+        if let Some(diagnostic) = &status.diagnostic {
+            text.push_str(&format!("Diagnostic-Code: smtp; {}\r\n", diagnostic));
+        }
+    }
+    text.push_str("\r\n");
+
+    text.push_str(&format!("--{}\r\n", boundary));
+    let mut data = text.into_bytes();
+    match envelope.ret {
+        Some(DsnRet::Full) => {
+            data.extend_from_slice(b"Content-Type: message/rfc822\r\n\r\n");
+            data.extend_from_slice(message);
+        }
+        _ => {
+            data.extend_from_slice(b"Content-Type: text/rfc822-headers\r\n\r\n");
+            data.extend_from_slice(headers(message));
+        }
+    }
+    if !data.ends_with(b"\r\n") {
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    Some(data)
+}
+
+fn headers(message: &[u8]) -> &[u8] {
+    match message.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => &message[..pos + 2],
+        None => message,
+    }
+}
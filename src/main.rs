@@ -7,9 +7,16 @@
 
 mod conf;
 mod constants;
+mod date;
+mod delivery;
+mod directory;
+mod dsn;
 mod manager;
+mod queue;
 mod smtpd;
 
+use std::sync::Arc;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let server_list = init("example/arcmail.json").await?;
@@ -20,31 +27,60 @@ async fn main() -> anyhow::Result<()> {
 
 async fn init(path: &str) -> anyhow::Result<manager::ServerList> {
     let config = conf::Config::load_path(path)?;
+    let queue = Arc::new(queue::Queue::open(&config.queue)?);
+    let recovered = queue.recover()?;
+    if recovered > 0 {
+        println!("Recovered {} queued messages", recovered);
+    }
 
+    let mut delivery = delivery::DeliveryAgent::new(queue.clone());
     let mut smtpds: Vec<smtpd::server::SmtpServer> = Vec::with_capacity(config.smtp_servers.len());
     for smtpd_config in &config.smtp_servers {
+        let ports = smtpd_config
+            .ports
+            .iter()
+            .map(|port| (port, false, smtpd::session::SmtpProtocol::Smtp))
+            .chain(
+                smtpd_config
+                    .tls_ports
+                    .iter()
+                    .map(|port| (port, true, smtpd::session::SmtpProtocol::Smtp)),
+            )
+            .chain(
+                smtpd_config
+                    .lmtp_ports
+                    .iter()
+                    .map(|port| (port, false, smtpd::session::SmtpProtocol::Lmtp)),
+            );
         let mut smtpd = smtpd::server::SmtpServer::new(
-            smtpd_config.binds.len() * smtpd_config.ports.len(),
+            smtpd_config.binds.len()
+                * (smtpd_config.ports.len()
+                    + smtpd_config.tls_ports.len()
+                    + smtpd_config.lmtp_ports.len()),
             smtpd_config.clone(),
+            queue.clone(),
         )?;
-        for (bind, port) in smtpd_config.binds.iter().zip(smtpd_config.ports.iter()) {
-            let port_str = port.to_string();
-            let mut addr = String::with_capacity(bind.len() + port_str.len() + 1);
-            addr.push_str(&bind);
-            addr.push_str(":");
-            addr.push_str(&port_str);
-            smtpd.add(&addr).await?;
+        for (port, implicit_tls, protocol) in ports {
+            for bind in &smtpd_config.binds {
+                let addr = format!("{}:{}", bind, port);
+                smtpd.add(&addr, implicit_tls, protocol).await?;
+            }
         }
+        delivery.add(smtpd_config.clone(), smtpd.directory());
         smtpds.push(smtpd);
     }
-    Ok(manager::ServerList { smtpds })
+    Ok(manager::ServerList {
+        smtpds,
+        delivery: Arc::new(delivery),
+    })
 }
 
 fn run(servers: manager::ServerList) -> anyhow::Result<manager::ServerControl> {
+    servers.delivery.clone().run();
     let smtpds_control = servers
         .smtpds
         .into_iter()
-        .map(|smtpd: smtpd::server::SmtpServer| smtpd.run())
+        .map(|smtpd: smtpd::server::SmtpServer| smtpd.run(servers.delivery.clone()))
         .collect();
 
     Ok(manager::ServerControl { smtpds_control })
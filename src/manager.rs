@@ -5,7 +5,9 @@
 // src/manager.rs
 // Manager Thread.
 
+use crate::delivery;
 use crate::smtpd::server;
+use std::sync::Arc;
 use tokio::sync::watch;
 
 trait Control {
@@ -24,4 +26,5 @@ impl ServerControl {
 
 pub struct ServerList {
     pub smtpds: Vec<server::SmtpServer>,
+    pub delivery: Arc<delivery::DeliveryAgent>,
 }
@@ -0,0 +1,187 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/queue.rs
+// Spool of accepted messages.
+
+use crate::conf;
+use crate::smtpd::envelope::SmtpEnvelope;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueEntry {
+    pub id: String,
+    pub received: SystemTime,
+    pub envelope: SmtpEnvelope,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub retry_at: Option<SystemTime>,
+    // The DELIVERBY ";N" deadline has passed and was reported.
+    #[serde(default)]
+    pub deliver_by_notified: bool,
+    // NOTIFY=DELAY recipients were told about the first deferral.
+    #[serde(default)]
+    pub delay_notified: bool,
+}
+
+// Each message is "<id>.msg" with the body and "<id>.env" with the
+// entry. The envelope is written last, so a message exists once its
+// envelope does.
+pub struct Queue {
+    path: PathBuf,
+    counter: AtomicU64,
+    entries: Mutex<BTreeMap<String, QueueEntry>>,
+    notify: Notify,
+}
+
+impl Queue {
+    pub fn open(config: &conf::ConfigQueue) -> anyhow::Result<Self> {
+        fs::create_dir_all(&config.path)?;
+        Ok(Queue {
+            path: PathBuf::from(&config.path),
+            counter: AtomicU64::new(0),
+            entries: Mutex::new(BTreeMap::new()),
+            notify: Notify::new(),
+        })
+    }
+
+    // Load entries left by a previous run and drop incomplete writes.
+    pub fn recover(&self) -> anyhow::Result<usize> {
+        let mut entries = BTreeMap::new();
+        let mut orphans = Vec::new();
+        for file in fs::read_dir(&self.path)? {
+            let path = file?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("env") => match load(&path) {
+                    Ok(entry) => {
+                        entries.insert(entry.id.clone(), entry);
+                    }
+                    Err(e) => eprintln!("{}: skipping queue entry: {}", path.display(), e),
+                },
+                Some("msg") if !path.with_extension("env").exists() => orphans.push(path),
+                Some("tmp") => orphans.push(path),
+                _ => {}
+            }
+        }
+        for path in orphans {
+            fs::remove_file(path)?;
+        }
+        let count = entries.len();
+        self.lock().extend(entries);
+        Ok(count)
+    }
+
+    // Store a message, returning once it is on disk.
+    pub async fn push(&self, envelope: SmtpEnvelope, body: Vec<u8>) -> anyhow::Result<String> {
+        let received = SystemTime::now();
+        let micros = received
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_micros())
+            .unwrap_or(0);
+        let id = format!(
+            "{}.{}.{}",
+            micros,
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        );
+        let entry = QueueEntry {
+            id: id.clone(),
+            received,
+            envelope,
+            attempts: 0,
+            retry_at: None,
+            deliver_by_notified: false,
+            delay_notified: false,
+        };
+        let data = serde_json::to_vec(&entry)?;
+        let path = self.path.clone();
+        let name = id.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            write_durable(&path.join(format!("{}.msg", name)), &body)?;
+            write_durable(&path.join(format!("{}.env", name)), &data)?;
+            File::open(&path)?.sync_all()?;
+            Ok(())
+        })
+        .await??;
+        self.lock().insert(id.clone(), entry);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    // Wait for a new message to be queued.
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    pub fn entries(&self) -> Vec<QueueEntry> {
+        self.lock().values().cloned().collect()
+    }
+
+    pub async fn body(&self, id: &str) -> anyhow::Result<Vec<u8>> {
+        let path = self.path.join(format!("{}.msg", id));
+        Ok(tokio::task::spawn_blocking(move || fs::read(path)).await??)
+    }
+
+    // Replace the envelope on disk, e.g. with the recipients still pending.
+    pub async fn update(&self, entry: QueueEntry) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(&entry)?;
+        let path = self.path.clone();
+        let name = entry.id.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            write_durable(&path.join(format!("{}.env", name)), &data)?;
+            File::open(&path)?.sync_all()?;
+            Ok(())
+        })
+        .await??;
+        self.lock().insert(entry.id.clone(), entry);
+        Ok(())
+    }
+
+    // The envelope goes first, so a crash cannot resurrect the message.
+    pub async fn remove(&self, id: &str) -> anyhow::Result<()> {
+        self.lock().remove(id);
+        let path = self.path.clone();
+        let name = id.to_string();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            fs::remove_file(path.join(format!("{}.env", name)))?;
+            File::open(&path)?.sync_all()?;
+            fs::remove_file(path.join(format!("{}.msg", name)))?;
+            Ok(())
+        })
+        .await?
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, QueueEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn load(path: &Path) -> anyhow::Result<QueueEntry> {
+    let entry: QueueEntry = serde_json::from_slice(&fs::read(path)?)?;
+    if !path.with_extension("msg").exists() {
+        return Err(anyhow::anyhow!("message body missing"));
+    }
+    Ok(entry)
+}
+
+// Write to a temporary file, sync it, then rename over the target.
+fn write_durable(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
@@ -5,58 +5,229 @@
 // src/smtpd/allow.rs
 // Check Command of SMTPd.
 
+use super::session::SmtpSessionStatus;
 use tokio::sync::mpsc;
 
+// Full reply catalog, not every reply is sent yet.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub enum SmtpError {
-    SyntaxError(&'static str),
-    ParamSyntaxError(&'static str),
-    CommandNotImplemented(&'static str),
-    BadSequence(&'static str),
+    SystemStatus,
+    HelpMessage,
+    ServiceReady,
+    ServiceClosing,
+    AuthSucceeded,
+    Ok,
+    Hello,
+    SenderOk,
+    RecipientOk,
+    UserNotLocalForward,
+    CannotVerify,
+    AuthContinue,
+    StartMailInput,
+    ServiceNotAvailable,
+    MailboxBusy,
+    LocalError,
+    InsufficientStorage,
+    MailboxFullTemporary,
+    TooManyRecipients,
+    TlsUnavailable,
+    AuthTemporaryFailure,
+    ParamsUnavailable,
+    SyntaxError,
+    LineTooLong,
+    InvalidCharacter,
+    ParamSyntaxError,
+    BadSenderSyntax,
+    BadRecipientSyntax,
+    AuthCanceled,
+    CommandNotImplemented,
+    BadSequence,
+    ImproperPipelining,
+    ParamNotImplemented,
+    StartTlsRequired,
+    AuthRequired,
+    AuthFailed,
+    EncryptionRequired,
+    MailboxUnavailable,
+    PolicyRejection,
+    UserNotLocal,
+    MailboxFull,
+    MessageTooBig,
+    MailboxNameNotAllowed,
+    UserAmbiguous,
+    NonAsciiAddress,
+    TransactionFailed,
+    RelayDenied,
+    ParamsNotRecognized,
 }
 
 impl SmtpError {
-    pub fn new(code: u16) -> Self {
-        match code {
-            500 => SmtpError::SyntaxError("500 Syntax error, command unrecognized\r\n"),
-            501 => SmtpError::ParamSyntaxError("501 Syntax error in parameters or arguments\r\n"),
-            502 => SmtpError::CommandNotImplemented("502 Command not implemented\r\n"),
-            503 => SmtpError::BadSequence("503 Bad sequence of commands\r\n"),
-            _ => SmtpError::SyntaxError("500 Syntax error, command unrecognized\r\n"),
+    // Reply code, RFC 3463 enhanced status code and default text.
+    pub fn detail(&self) -> (u16, Option<&'static str>, &'static str) {
+        match self {
+            SmtpError::SystemStatus => (211, Some("2.0.0"), "System status"),
+            SmtpError::HelpMessage => (214, Some("2.0.0"), "Help message"),
+            SmtpError::ServiceReady => (220, None, "Service ready"),
+            SmtpError::ServiceClosing => (221, Some("2.0.0"), "Bye"),
+            SmtpError::AuthSucceeded => (235, Some("2.7.0"), "Authentication successful"),
+            SmtpError::Ok => (250, Some("2.0.0"), "OK"),
+            SmtpError::Hello => (250, None, "Hello"),
+            SmtpError::SenderOk => (250, Some("2.1.0"), "OK"),
+            SmtpError::RecipientOk => (250, Some("2.1.5"), "OK"),
+            SmtpError::UserNotLocalForward => (251, Some("2.1.5"), "User not local; will forward"),
+            SmtpError::CannotVerify => (
+                252,
+                Some("2.5.0"),
+                "Cannot VRFY user, but will accept message and attempt delivery",
+            ),
+            SmtpError::AuthContinue => (334, None, ""),
+            SmtpError::StartMailInput => (354, None, "Start mail input; end with <CRLF>.<CRLF>"),
+            SmtpError::ServiceNotAvailable => (
+                421,
+                Some("4.3.2"),
+                "Service not available, closing transmission channel",
+            ),
+            SmtpError::MailboxBusy => (450, Some("4.2.1"), "Mailbox unavailable"),
+            SmtpError::LocalError => (451, Some("4.3.0"), "Local error in processing"),
+            SmtpError::InsufficientStorage => (452, Some("4.3.1"), "Insufficient system storage"),
+            SmtpError::MailboxFullTemporary => (452, Some("4.2.2"), "Mailbox full"),
+            SmtpError::TooManyRecipients => (452, Some("4.5.3"), "Too many recipients"),
+            SmtpError::TlsUnavailable => (
+                454,
+                Some("4.7.0"),
+                "TLS not available due to temporary reason",
+            ),
+            SmtpError::AuthTemporaryFailure => {
+                (454, Some("4.7.0"), "Temporary authentication failure")
+            }
+            SmtpError::ParamsUnavailable => (
+                455,
+                Some("4.5.4"),
+                "Server unable to accommodate parameters",
+            ),
+            SmtpError::SyntaxError => (500, Some("5.5.2"), "Syntax error, command unrecognized"),
+            SmtpError::LineTooLong => (500, Some("5.5.6"), "Line too long"),
+            SmtpError::InvalidCharacter => (500, Some("5.5.2"), "Syntax error, invalid character"),
+            SmtpError::ParamSyntaxError => (
+                501,
+                Some("5.5.4"),
+                "Syntax error in parameters or arguments",
+            ),
+            SmtpError::BadSenderSyntax => (501, Some("5.1.7"), "Bad sender address syntax"),
+            SmtpError::BadRecipientSyntax => (501, Some("5.1.3"), "Bad recipient address syntax"),
+            SmtpError::AuthCanceled => (501, Some("5.7.0"), "Authentication canceled"),
+            SmtpError::CommandNotImplemented => (502, Some("5.5.1"), "Command not implemented"),
+            SmtpError::BadSequence => (503, Some("5.5.1"), "Bad sequence of commands"),
+            SmtpError::ImproperPipelining => (
+                503,
+                Some("5.5.0"),
+                "Improper use of SMTP command pipelining",
+            ),
+            SmtpError::ParamNotImplemented => {
+                (504, Some("5.5.4"), "Command parameter not implemented")
+            }
+            SmtpError::StartTlsRequired => {
+                (530, Some("5.7.0"), "Must issue a STARTTLS command first")
+            }
+            SmtpError::AuthRequired => (530, Some("5.7.0"), "Authentication required"),
+            SmtpError::AuthFailed => (535, Some("5.7.8"), "Authentication credentials invalid"),
+            SmtpError::EncryptionRequired => (
+                538,
+                Some("5.7.11"),
+                "Encryption required for requested authentication mechanism",
+            ),
+            SmtpError::MailboxUnavailable => (550, Some("5.1.1"), "Mailbox unavailable"),
+            SmtpError::PolicyRejection => (550, Some("5.7.1"), "Rejected for policy reasons"),
+            SmtpError::UserNotLocal => (551, Some("5.1.6"), "User not local"),
+            SmtpError::MailboxFull => (552, Some("5.2.2"), "Mailbox full"),
+            SmtpError::MessageTooBig => (
+                552,
+                Some("5.3.4"),
+                "Message size exceeds fixed maximum message size",
+            ),
+            SmtpError::MailboxNameNotAllowed => (553, Some("5.1.3"), "Mailbox name not allowed"),
+            SmtpError::UserAmbiguous => (553, Some("5.1.4"), "User ambiguous"),
+            SmtpError::NonAsciiAddress => {
+                (553, Some("5.6.7"), "Non-ASCII addresses require SMTPUTF8")
+            }
+            SmtpError::TransactionFailed => (554, Some("5.0.0"), "Transaction failed"),
+            SmtpError::RelayDenied => (554, Some("5.7.1"), "Relay access denied"),
+            SmtpError::ParamsNotRecognized => (
+                555,
+                Some("5.5.4"),
+                "MAIL FROM/RCPT TO parameters not recognized or not implemented",
+            ),
         }
     }
 
-    pub fn message(&self) -> &'static str {
-        match self {
-            SmtpError::SyntaxError(msg) => msg,
-            SmtpError::ParamSyntaxError(msg) => msg,
-            SmtpError::CommandNotImplemented(msg) => msg,
-            SmtpError::BadSequence(msg) => msg,
+    pub fn message(&self) -> String {
+        self.message_lines(&[self.detail().2])
+    }
+
+    pub fn message_text(&self, text: &str) -> String {
+        self.message_lines(&[text])
+    }
+
+    // Reply with the enhanced status code of a delivery result.
+    pub fn message_status(&self, status: &str, text: &str) -> String {
+        format!("{} {} {}\r\n", self.detail().0, status, text)
+    }
+
+    pub fn message_lines<S: AsRef<str>>(&self, lines: &[S]) -> String {
+        let (code, status, _) = self.detail();
+        let mut message = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            let sep = if i + 1 == lines.len() { ' ' } else { '-' };
+            let line = line.as_ref();
+            match status {
+                Some(status) => {
+                    message.push_str(&format!("{}{}{} {}\r\n", code, sep, status, line))
+                }
+                None if line.is_empty() => message.push_str(&format!("{}{}\r\n", code, sep)),
+                None => message.push_str(&format!("{}{}{}\r\n", code, sep, line)),
+            }
         }
+        message
     }
 
     pub async fn return_code(self, writer: mpsc::Sender<String>) -> anyhow::Result<()> {
-        writer.send(self.message().to_string()).await?;
+        writer.send(self.message()).await?;
         Ok(())
     }
 
-    pub async fn get_message_bytes(code: u16) -> Vec<u8> {
-        Self::new(code).message().as_bytes().to_vec()
+    pub async fn return_text(self, writer: mpsc::Sender<String>, text: &str) -> anyhow::Result<()> {
+        writer.send(self.message_text(text)).await?;
+        Ok(())
+    }
+
+    pub async fn return_lines<S: AsRef<str>>(
+        self,
+        writer: mpsc::Sender<String>,
+        lines: &[S],
+    ) -> anyhow::Result<()> {
+        writer.send(self.message_lines(lines)).await?;
+        Ok(())
     }
 }
 
-const RFC5321_COMMANDS: [&str; 11] = [
-    "HELO", "EHLO", "MAIL", "RCPT", "DATA", "RSET", "NOOP", "QUIT", "VRFY", "EXPN", "HELP",
-];
+const ALLOW_COMMANDS: [&str; 4] = ["MAIL", "RCPT", "DATA", "BDAT"];
 
-const ALLOW_COMMANDS: [&str; 3] = ["HELO", "RSET", "QUTI"];
+pub fn check_status(command: &str, status: &SmtpSessionStatus) -> bool {
+    match command {
+        "MAIL" => matches!(status, SmtpSessionStatus::Hello),
+        "RCPT" => matches!(status, SmtpSessionStatus::Sender | SmtpSessionStatus::Rcpt),
+        "DATA" => matches!(status, SmtpSessionStatus::Rcpt),
+        "BDAT" => matches!(status, SmtpSessionStatus::Rcpt | SmtpSessionStatus::Chunk),
+        _ => true,
+    }
+}
 
 #[inline]
 pub fn check_command(command: &str) -> SmtpError {
-    if !RFC5321_COMMANDS.contains(&command) {
-        SmtpError::new(500)
-    } else if ALLOW_COMMANDS.contains(&command) {
-        SmtpError::new(503)
+    if ALLOW_COMMANDS.contains(&command) {
+        SmtpError::BadSequence
     } else {
-        SmtpError::new(502)
+        SmtpError::CommandNotImplemented
     }
 }
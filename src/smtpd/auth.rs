@@ -0,0 +1,143 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/smtpd/auth.rs
+// SMTP Authentication (RFC 4954).
+
+use crate::{conf, constants};
+use aws_lc_rs::{digest, hmac, pbkdf2, rand};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+
+pub const MECHANISMS: [&str; 5] = [
+    "PLAIN",
+    "LOGIN",
+    "CRAM-MD5",
+    "SCRAM-SHA-256",
+    "SCRAM-SHA-256-PLUS",
+];
+
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramCredentials {
+    pub fn new(password: &str, salt: Vec<u8>, iterations: u32) -> Self {
+        let mut salted = [0u8; digest::SHA256_OUTPUT_LEN];
+        let rounds = NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN);
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            rounds,
+            &salt,
+            password.as_bytes(),
+            &mut salted,
+        );
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &salted);
+        let client_key = hmac::sign(&key, b"Client Key");
+        let server_key = hmac::sign(&key, b"Server Key");
+        ScramCredentials {
+            salt,
+            iterations: rounds.get(),
+            stored_key: digest::digest(&digest::SHA256, client_key.as_ref())
+                .as_ref()
+                .to_vec(),
+            server_key: server_key.as_ref().to_vec(),
+        }
+    }
+}
+
+pub trait SmtpAuthenticator: Send + Sync {
+    // Check a plain text password of authcid.
+    fn check(&self, authcid: &str, password: &str) -> bool;
+
+    // Whether authcid may act as authzid.
+    fn authorize(&self, authzid: &str, authcid: &str) -> bool {
+        authzid.is_empty() || authzid == authcid
+    }
+
+    // Shared secret for CRAM-MD5, None unless stored in plain text.
+    fn secret(&self, _authcid: &str) -> Option<String> {
+        None
+    }
+
+    fn scram_sha256(&self, _authcid: &str) -> Option<ScramCredentials> {
+        None
+    }
+}
+
+struct ConfigUser {
+    password: String,
+    salt: Vec<u8>,
+}
+
+// Users listed in the server configuration.
+pub struct ConfigAuthenticator {
+    users: HashMap<String, ConfigUser>,
+}
+
+impl ConfigAuthenticator {
+    pub fn new(config: &conf::ConfigSmtpServerAuth) -> anyhow::Result<Self> {
+        let mut users = HashMap::with_capacity(config.users.len());
+        for user in &config.users {
+            let mut salt = vec![0u8; 16];
+            rand::fill(&mut salt)?;
+            users.insert(
+                user.name.clone(),
+                ConfigUser {
+                    password: user.password.clone(),
+                    salt,
+                },
+            );
+        }
+        Ok(ConfigAuthenticator { users })
+    }
+}
+
+impl SmtpAuthenticator for ConfigAuthenticator {
+    fn check(&self, authcid: &str, password: &str) -> bool {
+        self.users.get(authcid).is_some_and(|user| {
+            aws_lc_rs::constant_time::verify_slices_are_equal(
+                user.password.as_bytes(),
+                password.as_bytes(),
+            )
+            .is_ok()
+        })
+    }
+
+    fn secret(&self, authcid: &str) -> Option<String> {
+        self.users.get(authcid).map(|user| user.password.clone())
+    }
+
+    fn scram_sha256(&self, authcid: &str) -> Option<ScramCredentials> {
+        self.users.get(authcid).map(|user| {
+            ScramCredentials::new(
+                &user.password,
+                user.salt.clone(),
+                constants::SMTPD_SCRAM_ITERATIONS,
+            )
+        })
+    }
+}
+
+// Mechanisms offered on this session, in configuration order.
+// The -PLUS variant needs a TLS channel binding.
+pub fn mechanisms(config: &conf::ConfigSmtpServerAuth, tls: bool, binding: bool) -> Vec<&str> {
+    if config.require_tls && !tls {
+        return Vec::new();
+    }
+    config
+        .mechanisms
+        .iter()
+        .map(String::as_str)
+        .filter(|name| {
+            MECHANISMS
+                .iter()
+                .any(|mech| mech.eq_ignore_ascii_case(name))
+        })
+        .filter(|name| binding || !name.eq_ignore_ascii_case("SCRAM-SHA-256-PLUS"))
+        .collect()
+}
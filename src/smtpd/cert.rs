@@ -0,0 +1,160 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/smtpd/cert.rs
+// Load TLS certificates of SMTPd.
+
+use crate::conf;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::{CryptoProvider, aws_lc_rs};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+// Certificates keyed by lowercase hostname, "*.example.com" entries
+// cover exactly one extra label.
+#[derive(Debug)]
+pub struct SmtpCertStore {
+    certs: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SmtpCertStore {
+    fn get(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(key) = self.certs.get(&name) {
+            return Some(key);
+        }
+        let (_, parent) = name.split_once('.')?;
+        self.certs.get(&format!("*.{}", parent))
+    }
+}
+
+impl ResolvesServerCert for SmtpCertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        match client_hello.server_name().and_then(|name| self.get(name)) {
+            Some(key) => Some(key.clone()),
+            None => Some(self.default.clone()),
+        }
+    }
+}
+
+// Handshakes take the current acceptor, sessions already in TLS keep
+// the config they were established with.
+#[derive(Clone)]
+pub struct SmtpAcceptor {
+    current: watch::Receiver<TlsAcceptor>,
+}
+
+impl SmtpAcceptor {
+    pub fn new(
+        domain: &str,
+        config: &conf::ConfigSmtpServerTls,
+    ) -> anyhow::Result<(Self, watch::Sender<TlsAcceptor>)> {
+        let (tx, current) = watch::channel(load(domain, config)?);
+        Ok((SmtpAcceptor { current }, tx))
+    }
+
+    pub fn get(&self) -> TlsAcceptor {
+        self.current.borrow().clone()
+    }
+}
+
+fn stamps(config: &conf::ConfigSmtpServerTls) -> Vec<Option<SystemTime>> {
+    std::iter::once((&config.cert, &config.key))
+        .chain(config.certs.iter().map(|cert| (&cert.cert, &cert.key)))
+        .flat_map(|(cert, key)| [cert, key])
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .ok()
+        })
+        .collect()
+}
+
+// Reload on SIGHUP, or when a certificate or key file changes. A failed
+// reload is reported and the previous certificates stay in use.
+pub async fn reload(
+    config: Arc<conf::ConfigSmtpServer>,
+    tx: watch::Sender<TlsAcceptor>,
+) -> anyhow::Result<()> {
+    let tls = match &config.tls {
+        Some(tls) => tls,
+        None => return Ok(()),
+    };
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut ticker = match tls.reload_interval {
+        0 => None,
+        secs => Some(tokio::time::interval(Duration::from_secs(secs))),
+    };
+    let mut last = stamps(tls);
+    loop {
+        tokio::select! {
+            _ = tx.closed() => return Ok(()),
+            _ = hangup.recv() => {}
+            _ = async {
+                match ticker.as_mut() {
+                    Some(ticker) => ticker.tick().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if stamps(tls) == last {
+                    continue;
+                }
+            }
+        }
+        last = stamps(tls);
+        match load(&config.domain, tls) {
+            Ok(acceptor) => {
+                tx.send_replace(acceptor);
+            }
+            Err(e) => eprintln!(
+                "{}: keeping previous TLS certificates: {}",
+                config.domain, e
+            ),
+        }
+    }
+}
+
+fn load_key(provider: &CryptoProvider, cert: &str, key: &str) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    Ok(CertifiedKey::from_der(certs, key, provider)?)
+}
+
+fn load(domain: &str, config: &conf::ConfigSmtpServerTls) -> anyhow::Result<TlsAcceptor> {
+    let provider = Arc::new(aws_lc_rs::default_provider());
+    let default = Arc::new(load_key(&provider, &config.cert, &config.key)?);
+    let mut certs = HashMap::with_capacity(config.certs.len() + 1);
+    certs.insert(domain.to_ascii_lowercase(), default.clone());
+    for cert in &config.certs {
+        let key = load_key(&provider, &cert.cert, &cert.key)?;
+        certs.insert(cert.domain.to_ascii_lowercase(), Arc::new(key));
+    }
+    let server = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SmtpCertStore { certs, default }));
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+// The name the client asked for, if it is one of ours.
+pub fn server_name(domain: &str, config: &conf::ConfigSmtpServerTls, name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let parent = name.split_once('.').map(|(_, parent)| parent);
+    let found = std::iter::once(domain)
+        .chain(config.certs.iter().map(|cert| cert.domain.as_str()))
+        .any(|domain| match domain.strip_prefix("*.") {
+            Some(wildcard) => parent.is_some_and(|parent| parent.eq_ignore_ascii_case(wildcard)),
+            None => domain.eq_ignore_ascii_case(&name),
+        });
+    found.then_some(name)
+}
@@ -5,82 +5,730 @@
 // src/smtpd/cmd.rs
 // Handle SMTP Command.
 
-use super::{allow, session};
-use crate::constants;
-use crate::smtpd::stream::common::SmtpStreamTrait;
+use super::{allow, auth, envelope, esmtpd, sasl, session, stream, util};
+use crate::smtpd::stream::common::{SmtpStreamRead, SmtpStreamTrait};
+use crate::{conf, constants, delivery, directory};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+pub struct SmtpCommand {
+    pub name: &'static str,
+    pub syntax: &'static str,
+    pub help: &'static str,
+}
+
+pub const COMMANDS: [SmtpCommand; 15] = [
+    SmtpCommand {
+        name: "HELO",
+        syntax: "HELO <domain>",
+        help: "Identify the client and start an SMTP session",
+    },
+    SmtpCommand {
+        name: "EHLO",
+        syntax: "EHLO <domain>",
+        help: "Identify the client and list the supported extensions",
+    },
+    SmtpCommand {
+        name: "LHLO",
+        syntax: "LHLO <domain>",
+        help: "Identify the client and start an LMTP session",
+    },
+    SmtpCommand {
+        name: "STARTTLS",
+        syntax: "STARTTLS",
+        help: "Start TLS negotiation",
+    },
+    SmtpCommand {
+        name: "AUTH",
+        syntax: "AUTH <mechanism> [<initial-response>]",
+        help: "Authenticate with a SASL mechanism",
+    },
+    SmtpCommand {
+        name: "MAIL",
+        syntax: "MAIL FROM:<reverse-path> [<parameters>]",
+        help: "Start a mail transaction",
+    },
+    SmtpCommand {
+        name: "RCPT",
+        syntax: "RCPT TO:<forward-path> [<parameters>]",
+        help: "Add a recipient to the transaction",
+    },
+    SmtpCommand {
+        name: "DATA",
+        syntax: "DATA",
+        help: "Send the message, ended by <CRLF>.<CRLF>",
+    },
+    SmtpCommand {
+        name: "BDAT",
+        syntax: "BDAT <size> [LAST]",
+        help: "Send a chunk of the message",
+    },
+    SmtpCommand {
+        name: "RSET",
+        syntax: "RSET",
+        help: "Abort the current transaction",
+    },
+    SmtpCommand {
+        name: "NOOP",
+        syntax: "NOOP",
+        help: "Do nothing",
+    },
+    SmtpCommand {
+        name: "QUIT",
+        syntax: "QUIT",
+        help: "Close the session",
+    },
+    SmtpCommand {
+        name: "VRFY",
+        syntax: "VRFY <string>",
+        help: "Verify a user name or mailbox",
+    },
+    SmtpCommand {
+        name: "EXPN",
+        syntax: "EXPN <string>",
+        help: "Expand a mailing list",
+    },
+    SmtpCommand {
+        name: "HELP",
+        syntax: "HELP [<command>]",
+        help: "Show help on a command",
+    },
+];
+
+// Whether the command is usable on this session.
+fn available(session: &session::SmtpSession, name: &str) -> bool {
+    match name {
+        "HELO" | "EHLO" | "LHLO" => session.context.protocol.hello().contains(&name),
+        "STARTTLS" => session.context.acceptor.is_some() && !session.tls,
+        "AUTH" => session.config.auth.as_ref().is_some_and(|config| {
+            let binding = session.stream.channel_binding().is_some();
+            !auth::mechanisms(config, session.tls, binding).is_empty()
+        }),
+        "BDAT" => session.config.esmtp.chunking,
+        "VRFY" | "EXPN" => !matches!(
+            session.config.disclosure,
+            conf::ConfigSmtpServerDisclosure::Disabled
+        ),
+        _ => true,
+    }
+}
+
+// Only commands in the table reach the handlers, so HELP lists
+// exactly what is dispatched.
+pub fn lookup(
+    session: &session::SmtpSession,
+    name: &str,
+) -> Result<&'static SmtpCommand, allow::SmtpError> {
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) if available(session, name) => Ok(command),
+        // RFC 5321 3.5.3: a disabled VRFY still answers 252.
+        Some(_) if name == "VRFY" => Err(allow::SmtpError::CannotVerify),
+        // RFC 4954 4: AUTH waits for STARTTLS when TLS is required.
+        Some(_)
+            if name == "AUTH"
+                && session
+                    .config
+                    .auth
+                    .as_ref()
+                    .is_some_and(|config| config.require_tls && !session.tls) =>
+        {
+            Err(allow::SmtpError::EncryptionRequired)
+        }
+        Some(_) => Err(allow::SmtpError::CommandNotImplemented),
+        None => Err(allow::SmtpError::SyntaxError),
+    }
+}
+
+fn help(session: &session::SmtpSession, topic: Option<&str>) -> Option<Vec<String>> {
+    let config = &session.config.help;
+    let Some(topic) = topic else {
+        let mut lines: Vec<String> = if config.text.is_empty() {
+            constants::SMTPD_HELP
+                .iter()
+                .map(|line| line.to_string())
+                .collect()
+        } else {
+            config.text.clone()
+        };
+        let topics: Vec<&str> = COMMANDS
+            .iter()
+            .map(|command| command.name)
+            .filter(|name| available(session, name))
+            .collect();
+        lines.push(format!("Topics: {}", topics.join(" ")));
+        return Some(lines);
+    };
+    let topic = topic.to_ascii_uppercase();
+    if let Some((_, lines)) = config
+        .topics
+        .iter()
+        .find(|(name, lines)| name.eq_ignore_ascii_case(&topic) && !lines.is_empty())
+        && available(session, &topic)
+    {
+        return Some(lines.clone());
+    }
+    COMMANDS
+        .iter()
+        .find(|command| command.name == topic && available(session, command.name))
+        .map(|command| vec![command.syntax.to_string(), command.help.to_string()])
+}
 
 pub async fn global_command(
     session: &mut session::SmtpSession,
-    para: &Vec<&str>,
+    para: &[&str],
 ) -> anyhow::Result<bool> {
     match para[0] {
         "HELO" => {
             if para.len() == 2 {
                 session.client = para[1].to_string();
-                let message = format!("250 {} {}\r\n", session.config.hello, session.config.domain);
-                session.stream.get_writer().send(message).await?;
+                session.esmtp = false;
+                session.envelope = None;
+                let message = format!("{} {}", session.config.hello, session.domain);
+                allow::SmtpError::Hello
+                    .return_text(session.stream.get_writer(), &message)
+                    .await?;
                 session.status = session::SmtpSessionStatus::Hello;
-                session.stream.clear();
             } else {
-                allow::SmtpError::new(501)
+                allow::SmtpError::ParamSyntaxError
                     .return_code(session.stream.get_writer())
                     .await?;
             }
         }
-        "EHLO" => {
-            //TODO
-            return Ok(false);
-        }
-        "NOOP" => {
-            if para.len() != 1 {
-                allow::SmtpError::new(501)
+        "EHLO" | "LHLO" => {
+            if para.len() == 2 {
+                session.client = para[1].to_string();
+                session.esmtp = true;
+                session.envelope = None;
+                if session.config.esmtp.smtputf8 {
+                    session.stream.start_utf8();
+                }
+                esmtpd::run(session).await?;
+            } else {
+                allow::SmtpError::ParamSyntaxError
                     .return_code(session.stream.get_writer())
                     .await?;
             }
-            session
-                .stream
-                .get_writer()
-                .send(String::from("250 2.0.0 OK\r\n"))
+        }
+        "NOOP" => {
+            allow::SmtpError::Ok
+                .return_code(session.stream.get_writer())
                 .await?;
         }
         "QUIT" => {
             if para.len() == 1 {
                 session.status = session::SmtpSessionStatus::Stop;
-                session
-                    .stream
-                    .get_writer()
-                    .send(String::from("221 2.0.0 Bye\r\n"))
+                allow::SmtpError::ServiceClosing
+                    .return_code(session.stream.get_writer())
                     .await?;
             } else {
-                allow::SmtpError::new(501)
+                allow::SmtpError::ParamSyntaxError
                     .return_code(session.stream.get_writer())
                     .await?;
             }
         }
         "RSET" => {
             if para.len() == 1 {
-                session.status = session::SmtpSessionStatus::Start;
-                session.stream.clear();
+                session.reset();
+                allow::SmtpError::Ok
+                    .return_code(session.stream.get_writer())
+                    .await?;
             } else {
-                allow::SmtpError::new(501)
+                allow::SmtpError::ParamSyntaxError
+                    .return_code(session.stream.get_writer())
+                    .await?;
+            }
+        }
+        "STARTTLS" => {
+            let acceptor = match &session.context.acceptor {
+                Some(acceptor) => acceptor.get(),
+                None => return Ok(false),
+            };
+            if para.len() != 1 {
+                allow::SmtpError::ParamSyntaxError
                     .return_code(session.stream.get_writer())
                     .await?;
+                return Ok(true);
             }
+            allow::SmtpError::ServiceReady
+                .return_text(session.stream.get_writer(), "Ready to start TLS")
+                .await?;
+            let tx_len = session.config.expand.pipe_tx_len;
+            let rx_len = session.config.expand.pipe_rx_len;
+            session.stream.starttls(&acceptor, tx_len, rx_len).await?;
+            // RFC 3207: forget everything learned before the handshake.
+            session.tls = true;
+            session.update_domain();
+            session.esmtp = false;
+            session.client.clear();
+            session.user = None;
+            session.status = session::SmtpSessionStatus::Init;
+            session.reset();
         }
-        "VRFY" => {
-            //TODO
-            return Ok(false);
+        "AUTH" => {
+            let config = match &session.config.auth {
+                Some(config) => config,
+                None => return Ok(false),
+            };
+            if !session.esmtp
+                || session.user.is_some()
+                || !matches!(session.status, session::SmtpSessionStatus::Hello)
+            {
+                allow::SmtpError::BadSequence
+                    .return_code(session.stream.get_writer())
+                    .await?;
+                return Ok(true);
+            }
+            if para.len() < 2 || para.len() > 3 {
+                allow::SmtpError::ParamSyntaxError
+                    .return_code(session.stream.get_writer())
+                    .await?;
+                return Ok(true);
+            }
+            let mechanism = para[1].to_ascii_uppercase();
+            let binding = session.stream.channel_binding().is_some();
+            if !auth::mechanisms(config, session.tls, binding)
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&mechanism))
+            {
+                allow::SmtpError::ParamNotImplemented
+                    .return_text(
+                        session.stream.get_writer(),
+                        "Unrecognized authentication type",
+                    )
+                    .await?;
+                return Ok(true);
+            }
+            let initial = match para.get(2) {
+                Some(&"=") => Some(Vec::new()),
+                Some(data) => match STANDARD.decode(data) {
+                    Ok(data) => Some(data),
+                    Err(_) => {
+                        allow::SmtpError::ParamSyntaxError
+                            .return_code(session.stream.get_writer())
+                            .await?;
+                        return Ok(true);
+                    }
+                },
+                None => None,
+            };
+            match authenticate(session, &mechanism, initial).await? {
+                Ok(user) => {
+                    session.user = Some(user);
+                    allow::SmtpError::AuthSucceeded
+                        .return_code(session.stream.get_writer())
+                        .await?;
+                }
+                Err(error) => error.return_code(session.stream.get_writer()).await?,
+            }
+        }
+        "VRFY" | "EXPN" => {
+            let vrfy = para[0] == "VRFY";
+            if matches!(
+                session.config.disclosure,
+                conf::ConfigSmtpServerDisclosure::Authenticated
+            ) && session.user.is_none()
+            {
+                allow::SmtpError::AuthRequired
+                    .return_code(session.stream.get_writer())
+                    .await?;
+                return Ok(true);
+            }
+            if para.len() < 2 {
+                allow::SmtpError::ParamSyntaxError
+                    .return_code(session.stream.get_writer())
+                    .await?;
+                return Ok(true);
+            }
+            let directory = session.context.directory.clone();
+            let entries = directory.search(&para[1..].join(" "));
+            let writer = session.stream.get_writer();
+            match (vrfy, &entries[..]) {
+                (_, []) => {
+                    allow::SmtpError::MailboxUnavailable
+                        .return_code(writer)
+                        .await?
+                }
+                (true, [directory::DirectoryEntry::Mailbox(mailbox)]) => {
+                    allow::SmtpError::RecipientOk
+                        .return_text(writer, &mailbox.display())
+                        .await?;
+                }
+                (true, [directory::DirectoryEntry::Alias(_)]) => {
+                    allow::SmtpError::MailboxUnavailable
+                        .return_text(writer, "That is a mailing list, not a user")
+                        .await?;
+                }
+                (false, [directory::DirectoryEntry::Alias(address)]) => {
+                    let lines: Vec<String> = directory
+                        .expand(address)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|member| match directory.mailbox(member) {
+                            Some(mailbox) => mailbox.display(),
+                            None => format!("<{}>", member),
+                        })
+                        .collect();
+                    if lines.is_empty() {
+                        allow::SmtpError::MailboxUnavailable
+                            .return_code(writer)
+                            .await?;
+                    } else {
+                        allow::SmtpError::RecipientOk
+                            .return_lines(writer, &lines)
+                            .await?;
+                    }
+                }
+                (false, [directory::DirectoryEntry::Mailbox(_)]) => {
+                    allow::SmtpError::MailboxUnavailable
+                        .return_text(writer, "That is a user name, not a mailing list")
+                        .await?;
+                }
+                _ => allow::SmtpError::UserAmbiguous.return_code(writer).await?,
+            }
         }
         "HELP" => {
-            if para.len() == 1 {
-                session
-                    .stream
-                    .get_writer()
-                    .send(String::from(constants::SMTPD_HELP))
+            if para.len() > 2 {
+                allow::SmtpError::ParamSyntaxError
+                    .return_code(session.stream.get_writer())
+                    .await?;
+                return Ok(true);
+            }
+            match help(session, para.get(1).copied()) {
+                Some(lines) => {
+                    allow::SmtpError::HelpMessage
+                        .return_lines(session.stream.get_writer(), &lines)
+                        .await?;
+                }
+                None => {
+                    allow::SmtpError::ParamNotImplemented
+                        .return_text(session.stream.get_writer(), "HELP topic unknown")
+                        .await?;
+                }
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+pub async fn mail_command(
+    session: &mut session::SmtpSession,
+    para: &[&str],
+) -> anyhow::Result<bool> {
+    match para[0] {
+        "MAIL" => {
+            let (path, params) = match envelope::parse_path(&para[1..], "FROM:") {
+                Ok(res) => res,
+                Err(envelope::PathError::Syntax) => {
+                    allow::SmtpError::ParamSyntaxError
+                        .return_code(session.stream.get_writer())
+                        .await?;
+                    return Ok(true);
+                }
+                Err(envelope::PathError::Address) => {
+                    allow::SmtpError::BadSenderSyntax
+                        .return_code(session.stream.get_writer())
+                        .await?;
+                    return Ok(true);
+                }
+            };
+            let mut envelope = envelope::SmtpEnvelope::new(path);
+            if let Err(error) = esmtpd::check_mail_params(session, &params, &mut envelope) {
+                error.return_code(session.stream.get_writer()).await?;
+                return Ok(true);
+            }
+            envelope.params = params;
+            session.envelope = Some(envelope);
+            session.status = session::SmtpSessionStatus::Sender;
+            allow::SmtpError::SenderOk
+                .return_code(session.stream.get_writer())
+                .await?;
+        }
+        "RCPT" => {
+            let (path, params) = match envelope::parse_path(&para[1..], "TO:") {
+                Ok((path, _)) if path.is_empty() => {
+                    allow::SmtpError::ParamSyntaxError
+                        .return_code(session.stream.get_writer())
+                        .await?;
+                    return Ok(true);
+                }
+                Ok(res) => res,
+                Err(envelope::PathError::Syntax) => {
+                    allow::SmtpError::ParamSyntaxError
+                        .return_code(session.stream.get_writer())
+                        .await?;
+                    return Ok(true);
+                }
+                Err(envelope::PathError::Address) => {
+                    allow::SmtpError::BadRecipientSyntax
+                        .return_code(session.stream.get_writer())
+                        .await?;
+                    return Ok(true);
+                }
+            };
+            // RFC 5321 4.5.1: bare "postmaster" is the local postmaster.
+            let path = match path.eq_ignore_ascii_case("postmaster") {
+                true => format!("postmaster@{}", session.config.domain),
+                false => path,
+            };
+            if let Err(error) = check_recipient(session, &path) {
+                error.return_code(session.stream.get_writer()).await?;
+                return Ok(true);
+            }
+            let mut recipient = envelope::SmtpRecipient::new(path);
+            if let Err(error) = esmtpd::check_rcpt_params(session, &params, &mut recipient) {
+                error.return_code(session.stream.get_writer()).await?;
+                return Ok(true);
+            }
+            recipient.params = params;
+            if let Some(envelope) = session.envelope.as_mut() {
+                envelope.forward_paths.push(recipient);
+            }
+            session.status = session::SmtpSessionStatus::Rcpt;
+            allow::SmtpError::RecipientOk
+                .return_code(session.stream.get_writer())
+                .await?;
+        }
+        "DATA" => {
+            if para.len() != 1 {
+                allow::SmtpError::ParamSyntaxError
+                    .return_code(session.stream.get_writer())
+                    .await?;
+                return Ok(true);
+            }
+            if matches!(
+                session.envelope.as_ref().map(|envelope| &envelope.body),
+                Some(envelope::SmtpBody::BinaryMime)
+            ) {
+                allow::SmtpError::BadSequence
+                    .return_text(session.stream.get_writer(), "BINARYMIME requires BDAT")
+                    .await?;
+                return Ok(true);
+            }
+            if session.stream.pending() {
+                allow::SmtpError::ImproperPipelining
+                    .return_code(session.stream.get_writer())
+                    .await?;
+                return Ok(true);
+            }
+            session.status = session::SmtpSessionStatus::Data;
+            session.stream.start_data();
+            allow::SmtpError::StartMailInput
+                .return_code(session.stream.get_writer())
+                .await?;
+            let message = match read_body(session).await {
+                Some(Ok(())) => complete(session).await,
+                Some(Err(error)) => error.message().repeat(session.replies()),
+                None => {
+                    session.status = session::SmtpSessionStatus::Stop;
+                    return Ok(true);
+                }
+            };
+            session.reset();
+            session.stream.get_writer().send(message).await?;
+        }
+        "BDAT" => {
+            if !session.config.esmtp.chunking {
+                allow::SmtpError::CommandNotImplemented
+                    .return_code(session.stream.get_writer())
                     .await?;
+                return Ok(true);
             }
+            let (size, last) = match stream::common::parse_bdat(para) {
+                Some(res) => res,
+                None => {
+                    allow::SmtpError::ParamSyntaxError
+                        .return_code(session.stream.get_writer())
+                        .await?;
+                    return Ok(true);
+                }
+            };
+            session.status = session::SmtpSessionStatus::Chunk;
+            let message = match read_body(session).await {
+                Some(Ok(())) if last => {
+                    let reply = complete(session).await;
+                    session.reset();
+                    reply
+                }
+                Some(Ok(())) => {
+                    allow::SmtpError::Ok.message_text(&format!("{} octets received", size))
+                }
+                Some(Err(error)) => {
+                    let count = if last { session.replies() } else { 1 };
+                    session.reset();
+                    error.message().repeat(count)
+                }
+                None => {
+                    session.status = session::SmtpSessionStatus::Stop;
+                    return Ok(true);
+                }
+            };
+            session.stream.get_writer().send(message).await?;
         }
         _ => return Ok(false),
     }
     Ok(true)
 }
+
+// Local users must exist, other domains are relayed for authenticated
+// clients only.
+fn check_recipient(session: &session::SmtpSession, path: &str) -> Result<(), allow::SmtpError> {
+    let local = path
+        .rsplit_once('@')
+        .is_some_and(|(_, domain)| domain.eq_ignore_ascii_case(&session.config.domain));
+    if !local {
+        // LMTP is final delivery and never relays.
+        return match (session.context.protocol, &session.user) {
+            (session::SmtpProtocol::Smtp, Some(_)) => Ok(()),
+            _ => Err(allow::SmtpError::RelayDenied),
+        };
+    }
+    // Without a delivery block local mail could only wait in the queue.
+    let Some(delivery) = &session.config.delivery else {
+        return Err(allow::SmtpError::LocalError);
+    };
+    // The LMTP server knows its own users.
+    match delivery.lmtp.is_some() || session.context.directory.accepts(path) {
+        true => Ok(()),
+        false => Err(allow::SmtpError::MailboxUnavailable),
+    }
+}
+
+// The whole message has been received, queue it before replying.
+// LMTP delivers it instead and answers for each recipient.
+async fn complete(session: &mut session::SmtpSession) -> String {
+    let Some(envelope) = session.envelope.take() else {
+        return allow::SmtpError::BadSequence.message();
+    };
+    let body = std::mem::take(&mut session.body);
+    if let session::SmtpProtocol::Lmtp = session.context.protocol {
+        let outcomes = session.context.delivery.deliver_now(&envelope, body).await;
+        // RFC 2033 4.2: one reply per recipient, in RCPT order. The
+        // details stay in the log, the client only gets the status.
+        return envelope
+            .forward_paths
+            .iter()
+            .zip(outcomes)
+            .map(|(recipient, outcome)| match outcome {
+                delivery::Outcome::Delivered => {
+                    allow::SmtpError::Ok.message_text(&format!("<{}> Delivered", recipient.path))
+                }
+                delivery::Outcome::Deferred(status, text) => {
+                    eprintln!(
+                        "{}: delivery to {} deferred: {}",
+                        session.config.domain, recipient.path, text
+                    );
+                    allow::SmtpError::LocalError
+                        .message_status(&status, &format!("<{}> Delivery deferred", recipient.path))
+                }
+                delivery::Outcome::Failed(status, text) => {
+                    eprintln!(
+                        "{}: delivery to {} failed: {}",
+                        session.config.domain, recipient.path, text
+                    );
+                    allow::SmtpError::MailboxUnavailable
+                        .message_status(&status, &format!("<{}> Delivery failed", recipient.path))
+                }
+            })
+            .collect();
+    }
+    match session.context.queue.push(envelope, body).await {
+        Ok(id) => allow::SmtpError::Ok.message_text(&format!("OK queued as {}", id)),
+        Err(e) => {
+            eprintln!("{}: queue write failed: {}", session.config.domain, e);
+            allow::SmtpError::LocalError.message()
+        }
+    }
+}
+
+// Run a SASL exchange, returning the authenticated identity.
+async fn authenticate(
+    session: &mut session::SmtpSession,
+    mechanism: &str,
+    initial: Option<Vec<u8>>,
+) -> anyhow::Result<Result<String, allow::SmtpError>> {
+    let authenticator = match &session.context.authenticator {
+        Some(authenticator) => authenticator.clone(),
+        None => return Ok(Err(allow::SmtpError::AuthTemporaryFailure)),
+    };
+    let binding = session.stream.channel_binding().map(<[u8]>::to_vec);
+    let plus = session.config.auth.as_ref().is_some_and(|config| {
+        auth::mechanisms(config, session.tls, binding.is_some())
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case("SCRAM-SHA-256-PLUS"))
+    });
+    let context = sasl::SaslContext {
+        authenticator,
+        domain: session.domain.clone(),
+        binding,
+        plus,
+    };
+    let mut mechanism = match sasl::start(mechanism, context) {
+        Some(mechanism) => mechanism,
+        None => return Ok(Err(allow::SmtpError::ParamNotImplemented)),
+    };
+    let mut response = initial;
+    loop {
+        match mechanism.step(response.as_deref()) {
+            sasl::SaslStep::Challenge(data) => match challenge(session, &data).await? {
+                Ok(data) => response = Some(data),
+                Err(error) => return Ok(Err(error)),
+            },
+            sasl::SaslStep::Success(identity) => return Ok(Ok(identity)),
+            sasl::SaslStep::Failure(sasl::SaslError::Malformed) => {
+                return Ok(Err(allow::SmtpError::ParamSyntaxError));
+            }
+            sasl::SaslStep::Failure(sasl::SaslError::Failed) => {
+                return Ok(Err(allow::SmtpError::AuthFailed));
+            }
+        }
+    }
+}
+
+// Send a 334 challenge and decode the client's response.
+async fn challenge(
+    session: &mut session::SmtpSession,
+    data: &[u8],
+) -> anyhow::Result<Result<Vec<u8>, allow::SmtpError>> {
+    session.stream.start_auth();
+    allow::SmtpError::AuthContinue
+        .return_text(session.stream.get_writer(), &STANDARD.encode(data))
+        .await?;
+    let mut line = match session.stream.read().await {
+        Some(SmtpStreamRead::Line(line)) => line,
+        Some(SmtpStreamRead::Error(error)) => return Ok(Err(error.reply())),
+        Some(_) => return Ok(Err(allow::SmtpError::ParamSyntaxError)),
+        None => return Err(anyhow::anyhow!("connection closed during AUTH")),
+    };
+    util::remove_crlf(&mut line);
+    if line == "*" {
+        return Ok(Err(allow::SmtpError::AuthCanceled));
+    }
+    Ok(STANDARD
+        .decode(line.trim())
+        .map_err(|_| allow::SmtpError::ParamSyntaxError))
+}
+
+// Collect the chunks of DATA or BDAT into the session body.
+async fn read_body(session: &mut session::SmtpSession) -> Option<Result<(), allow::SmtpError>> {
+    let mut oversize = false;
+    loop {
+        match session.stream.read().await? {
+            SmtpStreamRead::Chunk(chunk) => {
+                if oversize || esmtpd::check_size(session, session.body.len() + chunk.len()) {
+                    oversize = true;
+                } else {
+                    session.body.extend_from_slice(&chunk);
+                }
+            }
+            SmtpStreamRead::DataEnd(error) => {
+                return Some(match (oversize, error) {
+                    (true, _) => Err(allow::SmtpError::MessageTooBig),
+                    (false, Some(error)) => Err(error.reply()),
+                    (false, None) => Ok(()),
+                });
+            }
+            SmtpStreamRead::Line(_) | SmtpStreamRead::Error(_) => continue,
+        }
+    }
+}
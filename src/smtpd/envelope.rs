@@ -0,0 +1,284 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/smtpd/envelope.rs
+// Envelope of SMTP mail transaction.
+
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+pub type SmtpParams = Vec<(String, Option<String>)>;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DsnRet {
+    Full,
+    Hdrs,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DsnNotify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SmtpRecipient {
+    pub path: String,
+    pub params: SmtpParams,
+    pub notify: Option<DsnNotify>,
+    pub orcpt: Option<(String, String)>,
+}
+
+impl SmtpRecipient {
+    pub fn new(path: String) -> Self {
+        SmtpRecipient {
+            path,
+            params: SmtpParams::new(),
+            notify: None,
+            orcpt: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeliverByMode {
+    Return,
+    Notify,
+}
+
+// DELIVERBY (RFC 2852).
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliverBy {
+    pub deadline: SystemTime,
+    pub mode: DeliverByMode,
+    pub trace: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TlsPolicy {
+    Default,
+    // REQUIRETLS (RFC 8689), relay only over TLS with a verified certificate.
+    Required,
+}
+
+impl TlsPolicy {
+    pub fn permits(&self, tls: bool, verified: bool) -> bool {
+        match self {
+            TlsPolicy::Required => tls && verified,
+            TlsPolicy::Default => true,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SmtpBody {
+    SevenBit,
+    EightBitMime,
+    BinaryMime,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SmtpEnvelope {
+    pub reverse_path: String,
+    pub forward_paths: Vec<SmtpRecipient>,
+    pub params: SmtpParams,
+    pub body: SmtpBody,
+    pub smtputf8: bool,
+    pub ret: Option<DsnRet>,
+    pub envid: Option<String>,
+    pub auth: Option<String>,
+    pub tls: TlsPolicy,
+    pub hold_until: Option<SystemTime>,
+    pub deliver_by: Option<DeliverBy>,
+    pub priority: i8,
+}
+
+impl SmtpEnvelope {
+    pub fn new(reverse_path: String) -> Self {
+        SmtpEnvelope {
+            reverse_path,
+            forward_paths: Vec::new(),
+            params: SmtpParams::new(),
+            body: SmtpBody::SevenBit,
+            smtputf8: false,
+            ret: None,
+            envid: None,
+            auth: None,
+            tls: TlsPolicy::Default,
+            hold_until: None,
+            deliver_by: None,
+            priority: 0,
+        }
+    }
+}
+
+pub enum PathError {
+    Syntax,
+    Address,
+}
+
+// Parse "FROM:<path> params" or "TO:<path> params".
+pub fn parse_path(para: &[&str], keyword: &str) -> Result<(String, SmtpParams), PathError> {
+    let args = para.join(" ");
+    let rest = match args.get(..keyword.len()) {
+        Some(head) if head.eq_ignore_ascii_case(keyword) => args[keyword.len()..].trim_start(),
+        _ => return Err(PathError::Syntax),
+    };
+    let rest = rest.strip_prefix('<').ok_or(PathError::Syntax)?;
+    let end = rest.find('>').ok_or(PathError::Syntax)?;
+    let path = strip_route(&rest[..end]);
+    if !path.is_empty() && !check_address(path) {
+        return Err(PathError::Address);
+    }
+    let mut params = SmtpParams::new();
+    for param in rest[end + 1..].split_whitespace() {
+        let (key, value) = match param.split_once('=') {
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (param, None),
+        };
+        if key.is_empty() {
+            return Err(PathError::Syntax);
+        }
+        params.push((key.to_ascii_uppercase(), value));
+    }
+    Ok((path.to_string(), params))
+}
+
+fn strip_route(path: &str) -> &str {
+    match path.starts_with('@') {
+        true => path
+            .split_once(':')
+            .map(|(_, mailbox)| mailbox)
+            .unwrap_or(path),
+        false => path,
+    }
+}
+
+fn check_address(path: &str) -> bool {
+    if path.eq_ignore_ascii_case("postmaster") {
+        return true;
+    }
+    match path.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !path.chars().any(|c| c.is_whitespace() || c.is_control())
+        }
+        None => false,
+    }
+}
+
+// Decode xtext (RFC 3461 4), "+" followed by two upper case hex digits.
+pub fn decode_xtext(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut data = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                let hex = text.get(i + 1..i + 3)?;
+                if !hex
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(&b))
+                {
+                    return None;
+                }
+                data.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'!'..=b'~' if bytes[i] != b'=' => {
+                data.push(bytes[i]);
+                i += 1;
+            }
+            _ => return None,
+        }
+    }
+    String::from_utf8(data).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(args: &str, keyword: &str) -> Result<(String, SmtpParams), PathError> {
+        let para: Vec<&str> = args.split_whitespace().collect();
+        parse_path(&para, keyword)
+    }
+
+    #[test]
+    fn decodes_xtext() {
+        assert_eq!(
+            decode_xtext("user+2Bfolder"),
+            Some("user+folder".to_string())
+        );
+        assert_eq!(decode_xtext("a+3Db"), Some("a=b".to_string()));
+        assert_eq!(decode_xtext("plain"), Some("plain".to_string()));
+    }
+
+    #[test]
+    fn rejects_malformed_xtext() {
+        for text in ["+", "+2", "+2b", "+XX", "a+G0", "a=b", "a b", "+FF"] {
+            assert_eq!(decode_xtext(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn parses_path_and_params() {
+        let (address, params) = path("FROM:<user@example.com> SIZE=100 smtputf8", "FROM:")
+            .ok()
+            .unwrap();
+        assert_eq!(address, "user@example.com");
+        assert_eq!(
+            params,
+            vec![
+                ("SIZE".to_string(), Some("100".to_string())),
+                ("SMTPUTF8".to_string(), None),
+            ]
+        );
+        let (address, _) = path("to: <postmaster>", "TO:").ok().unwrap();
+        assert_eq!(address, "postmaster");
+        let (address, _) = path("FROM:<>", "FROM:").ok().unwrap();
+        assert_eq!(address, "");
+    }
+
+    #[test]
+    fn strips_source_route() {
+        let (address, _) = path("TO:<@relay.example,@hop.example:user@example.com>", "TO:")
+            .ok()
+            .unwrap();
+        assert_eq!(address, "user@example.com");
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        for args in [
+            "FROM:user@example.com",
+            "FROM:<user@example.com",
+            "TO:<a@b>",
+            "FROM:<a@b> =x",
+        ] {
+            assert!(
+                matches!(path(args, "FROM:"), Err(PathError::Syntax)),
+                "{}",
+                args
+            );
+        }
+        for args in ["FROM:<user>", "FROM:<@example.com>", "FROM:<user@>"] {
+            assert!(
+                matches!(path(args, "FROM:"), Err(PathError::Address)),
+                "{}",
+                args
+            );
+        }
+    }
+}
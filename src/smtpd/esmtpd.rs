@@ -5,19 +5,386 @@
 // src/smtpd/esmtpd.rs
 // Expand SMTP Server.
 
-use super::{session, util};
+use super::{allow, auth, envelope, session};
+use crate::date;
 use crate::smtpd::stream::common::SmtpStreamTrait;
+use std::time::{Duration, SystemTime};
 
-pub enum EsmtpStatus {
-    Empty,
+pub enum EsmtpExtension {
+    Size(usize),
+    Pipelining,
+    EightBitMime,
+    Chunking,
+    BinaryMime,
+    SmtpUtf8,
+    Dsn,
+    RequireTls,
+    FutureRelease(u64, String),
+    DeliverBy,
+    MtPriority,
+    StartTls,
+    Auth(Vec<String>),
+    EnhancedStatusCodes,
+    Help,
 }
 
-const ALLOW_EXPEND_MESSAGE: [&str; 1] = [""];
+impl EsmtpExtension {
+    pub fn keyword(&self) -> String {
+        match self {
+            EsmtpExtension::Size(0) => String::from("SIZE"),
+            EsmtpExtension::Size(size) => format!("SIZE {}", size),
+            EsmtpExtension::Pipelining => String::from("PIPELINING"),
+            EsmtpExtension::EightBitMime => String::from("8BITMIME"),
+            EsmtpExtension::Chunking => String::from("CHUNKING"),
+            EsmtpExtension::BinaryMime => String::from("BINARYMIME"),
+            EsmtpExtension::SmtpUtf8 => String::from("SMTPUTF8"),
+            EsmtpExtension::Dsn => String::from("DSN"),
+            EsmtpExtension::RequireTls => String::from("REQUIRETLS"),
+            EsmtpExtension::FutureRelease(interval, time) => {
+                format!("FUTURERELEASE {} {}", interval, time)
+            }
+            EsmtpExtension::DeliverBy => String::from("DELIVERBY"),
+            EsmtpExtension::MtPriority => String::from("MT-PRIORITY"),
+            EsmtpExtension::StartTls => String::from("STARTTLS"),
+            EsmtpExtension::Auth(mechanisms) => format!("AUTH {}", mechanisms.join(" ")),
+            EsmtpExtension::EnhancedStatusCodes => String::from("ENHANCEDSTATUSCODES"),
+            EsmtpExtension::Help => String::from("HELP"),
+        }
+    }
+}
+
+pub fn extensions(session: &session::SmtpSession) -> Vec<EsmtpExtension> {
+    let config = &session.config.esmtp;
+    let queued = session.context.protocol.queued();
+    let mut list = vec![EsmtpExtension::Size(session.config.max_message_size)];
+    if config.pipelining {
+        list.push(EsmtpExtension::Pipelining);
+    }
+    if config.eight_bit_mime {
+        list.push(EsmtpExtension::EightBitMime);
+    }
+    if config.chunking {
+        list.push(EsmtpExtension::Chunking);
+        if config.binary_mime {
+            list.push(EsmtpExtension::BinaryMime);
+        }
+    }
+    if config.smtputf8 {
+        list.push(EsmtpExtension::SmtpUtf8);
+    }
+    if config.dsn && queued {
+        list.push(EsmtpExtension::Dsn);
+    }
+    if config.requiretls && session.tls {
+        list.push(EsmtpExtension::RequireTls);
+    }
+    if config.futurerelease && queued {
+        let max = session.config.max_hold_time;
+        let until = SystemTime::now() + Duration::from_secs(max);
+        list.push(EsmtpExtension::FutureRelease(
+            max,
+            date::format_datetime(until),
+        ));
+    }
+    if config.deliverby && queued {
+        list.push(EsmtpExtension::DeliverBy);
+    }
+    if config.mt_priority && queued {
+        list.push(EsmtpExtension::MtPriority);
+    }
+    if session.context.acceptor.is_some() && !session.tls {
+        list.push(EsmtpExtension::StartTls);
+    }
+    if let Some(config) = &session.config.auth {
+        let binding = session.stream.channel_binding().is_some();
+        let mechanisms = auth::mechanisms(config, session.tls, binding);
+        if !mechanisms.is_empty() {
+            list.push(EsmtpExtension::Auth(
+                mechanisms
+                    .iter()
+                    .map(|name| name.to_ascii_uppercase())
+                    .collect(),
+            ));
+        }
+    }
+    list.push(EsmtpExtension::EnhancedStatusCodes);
+    if config.help {
+        list.push(EsmtpExtension::Help);
+    }
+    list
+}
+
+pub fn check_mail_params(
+    session: &session::SmtpSession,
+    params: &envelope::SmtpParams,
+    envelope: &mut envelope::SmtpEnvelope,
+) -> Result<(), allow::SmtpError> {
+    if !params.is_empty() && !session.esmtp {
+        return Err(allow::SmtpError::ParamsNotRecognized);
+    }
+    let queued = session.context.protocol.queued();
+    let now = SystemTime::now();
+    for (key, value) in params {
+        match (key.as_str(), value) {
+            ("SIZE", Some(value)) => {
+                if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(allow::SmtpError::ParamSyntaxError);
+                }
+                // Too many digits for usize is still just too big.
+                let size = value.parse().unwrap_or(usize::MAX);
+                if check_size(session, size) {
+                    return Err(allow::SmtpError::MessageTooBig);
+                }
+            }
+            ("BODY", Some(value)) if value.eq_ignore_ascii_case("7BIT") => {
+                envelope.body = envelope::SmtpBody::SevenBit;
+            }
+            ("BODY", Some(value))
+                if value.eq_ignore_ascii_case("8BITMIME")
+                    && session.config.esmtp.eight_bit_mime =>
+            {
+                envelope.body = envelope::SmtpBody::EightBitMime;
+            }
+            ("BODY", Some(value))
+                if value.eq_ignore_ascii_case("BINARYMIME")
+                    && session.config.esmtp.chunking
+                    && session.config.esmtp.binary_mime =>
+            {
+                envelope.body = envelope::SmtpBody::BinaryMime;
+            }
+            ("BODY", Some(_)) => {
+                return Err(allow::SmtpError::ParamsNotRecognized);
+            }
+            ("RET", Some(value)) if session.config.esmtp.dsn && queued => {
+                envelope.ret = match value.to_ascii_uppercase().as_str() {
+                    "FULL" => Some(envelope::DsnRet::Full),
+                    "HDRS" => Some(envelope::DsnRet::Hdrs),
+                    _ => return Err(allow::SmtpError::ParamSyntaxError),
+                };
+            }
+            ("ENVID", Some(value)) if session.config.esmtp.dsn && queued => {
+                let envid =
+                    envelope::decode_xtext(value).ok_or(allow::SmtpError::ParamSyntaxError)?;
+                if envid.is_empty() || envid.len() > 100 {
+                    return Err(allow::SmtpError::ParamSyntaxError);
+                }
+                envelope.envid = Some(envid);
+            }
+            ("AUTH", Some(value)) if session.config.auth.is_some() => {
+                let mailbox =
+                    envelope::decode_xtext(value).ok_or(allow::SmtpError::ParamSyntaxError)?;
+                // RFC 4954: only trusted once the client has authenticated.
+                if session.user.is_some() && mailbox != "<>" {
+                    envelope.auth = Some(mailbox);
+                }
+            }
+            ("REQUIRETLS", None) if session.config.esmtp.requiretls && session.tls => {
+                envelope.tls = envelope::TlsPolicy::Required;
+            }
+            ("HOLDFOR", Some(value)) if session.config.esmtp.futurerelease && queued => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| allow::SmtpError::ParamSyntaxError)?;
+                if envelope.hold_until.is_some() || secs > session.config.max_hold_time {
+                    return Err(allow::SmtpError::ParamSyntaxError);
+                }
+                envelope.hold_until = Some(now + Duration::from_secs(secs));
+            }
+            ("HOLDUNTIL", Some(value)) if session.config.esmtp.futurerelease && queued => {
+                let until =
+                    date::parse_datetime(value).ok_or(allow::SmtpError::ParamSyntaxError)?;
+                let max = now + Duration::from_secs(session.config.max_hold_time);
+                if envelope.hold_until.is_some() || until > max {
+                    return Err(allow::SmtpError::ParamSyntaxError);
+                }
+                envelope.hold_until = Some(until.max(now));
+            }
+            ("BY", Some(value)) if session.config.esmtp.deliverby && queued => {
+                if envelope.deliver_by.is_some() {
+                    return Err(allow::SmtpError::ParamSyntaxError);
+                }
+                envelope.deliver_by = Some(parse_by(value, now)?);
+            }
+            ("MT-PRIORITY", Some(value)) if session.config.esmtp.mt_priority && queued => {
+                let digit = value.strip_prefix(['-', '+']).unwrap_or(value);
+                if digit.len() != 1 || !digit.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(allow::SmtpError::ParamSyntaxError);
+                }
+                envelope.priority = value
+                    .parse()
+                    .map_err(|_| allow::SmtpError::ParamSyntaxError)?;
+            }
+            ("SMTPUTF8", None) if session.config.esmtp.smtputf8 => {
+                envelope.smtputf8 = true;
+            }
+            _ => return Err(allow::SmtpError::ParamsNotRecognized),
+        }
+    }
+    // A message held past its deadline could never be delivered in time.
+    if let (Some(until), Some(by)) = (envelope.hold_until, &envelope.deliver_by)
+        && by.deadline < until
+    {
+        return Err(allow::SmtpError::ParamSyntaxError);
+    }
+    if !envelope.reverse_path.is_ascii() && !envelope.smtputf8 {
+        return Err(allow::SmtpError::NonAsciiAddress);
+    }
+    Ok(())
+}
+
+pub fn check_rcpt_params(
+    session: &session::SmtpSession,
+    params: &envelope::SmtpParams,
+    recipient: &mut envelope::SmtpRecipient,
+) -> Result<(), allow::SmtpError> {
+    if !params.is_empty() && !session.esmtp {
+        return Err(allow::SmtpError::ParamsNotRecognized);
+    }
+    let queued = session.context.protocol.queued();
+    for (key, value) in params {
+        match (key.as_str(), value) {
+            ("NOTIFY", Some(value)) if session.config.esmtp.dsn && queued => {
+                recipient.notify = Some(parse_notify(value)?);
+            }
+            ("ORCPT", Some(value)) if session.config.esmtp.dsn && queued => {
+                let (kind, address) = value
+                    .split_once(';')
+                    .ok_or(allow::SmtpError::ParamSyntaxError)?;
+                let address =
+                    envelope::decode_xtext(address).ok_or(allow::SmtpError::ParamSyntaxError)?;
+                if kind.is_empty() || address.is_empty() {
+                    return Err(allow::SmtpError::ParamSyntaxError);
+                }
+                recipient.orcpt = Some((kind.to_string(), address));
+            }
+            _ => return Err(allow::SmtpError::ParamsNotRecognized),
+        }
+    }
+    let path = &recipient.path;
+    let smtputf8 = session
+        .envelope
+        .as_ref()
+        .is_some_and(|envelope| envelope.smtputf8);
+    if !path.is_ascii() && !smtputf8 {
+        return Err(allow::SmtpError::NonAsciiAddress);
+    }
+    Ok(())
+}
+
+// "BY=" by-time ";" by-mode [by-trace], by-time in seconds from now.
+fn parse_by(value: &str, now: SystemTime) -> Result<envelope::DeliverBy, allow::SmtpError> {
+    let (time, mode) = value
+        .split_once(';')
+        .ok_or(allow::SmtpError::ParamSyntaxError)?;
+    let digits = time.strip_prefix(['-', '+']).unwrap_or(time);
+    if digits.is_empty() || digits.len() > 9 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(allow::SmtpError::ParamSyntaxError);
+    }
+    let time: i64 = time
+        .parse()
+        .map_err(|_| allow::SmtpError::ParamSyntaxError)?;
+    let (mode, trace) = match mode.to_ascii_uppercase().as_str() {
+        "R" => (envelope::DeliverByMode::Return, false),
+        "RT" => (envelope::DeliverByMode::Return, true),
+        "N" => (envelope::DeliverByMode::Notify, false),
+        "NT" => (envelope::DeliverByMode::Notify, true),
+        _ => return Err(allow::SmtpError::ParamSyntaxError),
+    };
+    // RFC 2852 4.1.4: a return deadline must lie in the future.
+    if matches!(mode, envelope::DeliverByMode::Return) && time <= 0 {
+        return Err(allow::SmtpError::ParamSyntaxError);
+    }
+    let offset = Duration::from_secs(time.unsigned_abs());
+    let deadline = match time < 0 {
+        true => now.checked_sub(offset).unwrap_or(now),
+        false => now + offset,
+    };
+    Ok(envelope::DeliverBy {
+        deadline,
+        mode,
+        trace,
+    })
+}
+
+fn parse_notify(value: &str) -> Result<envelope::DsnNotify, allow::SmtpError> {
+    let mut notify = envelope::DsnNotify {
+        success: false,
+        failure: false,
+        delay: false,
+    };
+    let list: Vec<String> = value.split(',').map(str::to_ascii_uppercase).collect();
+    if list.len() == 1 && list[0] == "NEVER" {
+        return Ok(notify);
+    }
+    for item in list {
+        match item.as_str() {
+            "SUCCESS" => notify.success = true,
+            "FAILURE" => notify.failure = true,
+            "DELAY" => notify.delay = true,
+            _ => return Err(allow::SmtpError::ParamSyntaxError),
+        }
+    }
+    Ok(notify)
+}
+
+#[inline]
+pub fn check_size(session: &session::SmtpSession, size: usize) -> bool {
+    session.config.max_message_size != 0 && size > session.config.max_message_size
+}
 
 pub async fn run(session: &mut session::SmtpSession) -> anyhow::Result<()> {
-    let mut messages = ALLOW_EXPEND_MESSAGE.to_vec();
-    messages[0] = &session.config.domain;
-    util::write_multi_response(session.stream.get_writer(), messages).await?;
+    let mut messages = vec![format!("{} {}", session.domain, session.config.hello)];
+    messages.extend(extensions(session).iter().map(EsmtpExtension::keyword));
+    allow::SmtpError::Hello
+        .return_lines(session.stream.get_writer(), &messages)
+        .await?;
     session.status = session::SmtpSessionStatus::Hello;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn by(value: &str, now: SystemTime) -> Option<envelope::DeliverBy> {
+        parse_by(value, now).ok()
+    }
+
+    #[test]
+    fn parses_time_and_mode() {
+        let now = SystemTime::now();
+        let notify = by("120;N", now).unwrap();
+        assert_eq!(notify.deadline, now + Duration::from_secs(120));
+        assert!(matches!(notify.mode, envelope::DeliverByMode::Notify));
+        assert!(!notify.trace);
+        let ret = by("+60;rt", now).unwrap();
+        assert_eq!(ret.deadline, now + Duration::from_secs(60));
+        assert!(matches!(ret.mode, envelope::DeliverByMode::Return));
+        assert!(ret.trace);
+    }
+
+    #[test]
+    fn negative_time_only_notifies() {
+        let now = SystemTime::now();
+        let notify = by("-30;N", now).unwrap();
+        assert_eq!(notify.deadline, now - Duration::from_secs(30));
+        assert!(by("-30;R", now).is_none());
+        assert!(by("0;R", now).is_none());
+    }
+
+    #[test]
+    fn rejects_missing_or_invalid_mode() {
+        let now = SystemTime::now();
+        for value in ["120", "120;", "120;X", "120;NN", "120;TN"] {
+            assert!(by(value, now).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_time() {
+        let now = SystemTime::now();
+        for value in [";N", "+;N", "1x;N", "1 ;N", "1000000000;N"] {
+            assert!(by(value, now).is_none(), "{}", value);
+        }
+    }
+}
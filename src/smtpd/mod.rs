@@ -6,9 +6,13 @@
 // SMTPd module.
 
 mod allow;
+mod auth;
+mod cert;
 mod cmd;
+pub mod envelope;
 mod esmtpd;
+mod sasl;
 pub mod server;
-mod session;
-mod stream;
+pub mod session;
+pub mod stream;
 mod util;
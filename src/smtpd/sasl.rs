@@ -0,0 +1,395 @@
+// Copyright (c) 2026 Arc Asumity
+// Licensed under the GPLv3 or later License.
+// See LICENSE file for details.
+//
+// src/smtpd/sasl.rs
+// SASL mechanisms (RFC 4422) as protocol independent state machines.
+
+use super::auth::{ScramCredentials, SmtpAuthenticator};
+use crate::constants;
+use aws_lc_rs::{constant_time, digest, hmac, rand};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use md5::{Digest, Md5};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub enum SaslError {
+    Malformed,
+    Failed,
+}
+
+pub enum SaslStep {
+    Challenge(Vec<u8>),
+    Success(String),
+    Failure(SaslError),
+}
+
+pub struct SaslContext {
+    pub authenticator: Arc<dyn SmtpAuthenticator>,
+    pub domain: String,
+    // tls-exporter channel binding (RFC 9266), TLS 1.3 only.
+    pub binding: Option<Vec<u8>>,
+    // Whether SCRAM-SHA-256-PLUS was advertised.
+    pub plus: bool,
+}
+
+// The first step gets the initial response if the client sent one,
+// every later step gets the decoded reply to the previous challenge.
+pub trait SaslMechanism: Send {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep;
+}
+
+pub fn start(name: &str, context: SaslContext) -> Option<Box<dyn SaslMechanism>> {
+    match name.to_ascii_uppercase().as_str() {
+        "PLAIN" => Some(Box::new(Plain { context })),
+        "LOGIN" => Some(Box::new(Login {
+            context,
+            username: None,
+        })),
+        "CRAM-MD5" => Some(Box::new(CramMd5 {
+            context,
+            challenge: None,
+        })),
+        "SCRAM-SHA-256" => Some(Box::new(Scram::new(context, false))),
+        "SCRAM-SHA-256-PLUS" if context.binding.is_some() => {
+            Some(Box::new(Scram::new(context, true)))
+        }
+        _ => None,
+    }
+}
+
+fn finish(context: &SaslContext, authzid: &str, authcid: &str) -> SaslStep {
+    if !context.authenticator.authorize(authzid, authcid) {
+        return SaslStep::Failure(SaslError::Failed);
+    }
+    let identity = if authzid.is_empty() { authcid } else { authzid };
+    SaslStep::Success(identity.to_string())
+}
+
+// PLAIN (RFC 4616): authzid NUL authcid NUL passwd.
+struct Plain {
+    context: SaslContext,
+}
+
+impl SaslMechanism for Plain {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let response = match response {
+            Some(response) => response,
+            None => return SaslStep::Challenge(Vec::new()),
+        };
+        let text = match std::str::from_utf8(response) {
+            Ok(text) => text,
+            Err(_) => return SaslStep::Failure(SaslError::Malformed),
+        };
+        let parts: Vec<&str> = text.split('\0').collect();
+        let [authzid, authcid, password] = parts[..] else {
+            return SaslStep::Failure(SaslError::Malformed);
+        };
+        if authcid.is_empty() {
+            return SaslStep::Failure(SaslError::Malformed);
+        }
+        if !self.context.authenticator.check(authcid, password) {
+            return SaslStep::Failure(SaslError::Failed);
+        }
+        finish(&self.context, authzid, authcid)
+    }
+}
+
+// LOGIN: username and password prompted one after another.
+struct Login {
+    context: SaslContext,
+    username: Option<String>,
+}
+
+impl SaslMechanism for Login {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let text = match response.map(|data| String::from_utf8(data.to_vec())) {
+            Some(Ok(text)) => text,
+            Some(Err(_)) => return SaslStep::Failure(SaslError::Malformed),
+            None => return SaslStep::Challenge(b"Username:".to_vec()),
+        };
+        match &self.username {
+            None => {
+                self.username = Some(text);
+                SaslStep::Challenge(b"Password:".to_vec())
+            }
+            Some(username) => {
+                if !self.context.authenticator.check(username, &text) {
+                    return SaslStep::Failure(SaslError::Failed);
+                }
+                finish(&self.context, "", username)
+            }
+        }
+    }
+}
+
+// CRAM-MD5 (RFC 2195): "user hex(HMAC-MD5(secret, challenge))".
+struct CramMd5 {
+    context: SaslContext,
+    challenge: Option<String>,
+}
+
+impl SaslMechanism for CramMd5 {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let challenge = match (&self.challenge, response) {
+            (None, None) => {
+                let challenge = format!(
+                    "<{}.{}@{}>",
+                    nonce_u64(),
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|time| time.as_secs())
+                        .unwrap_or(0),
+                    self.context.domain
+                );
+                self.challenge = Some(challenge.clone());
+                return SaslStep::Challenge(challenge.into_bytes());
+            }
+            (Some(challenge), Some(_)) => challenge,
+            _ => return SaslStep::Failure(SaslError::Malformed),
+        };
+        let text = match response.map(std::str::from_utf8) {
+            Some(Ok(text)) => text,
+            _ => return SaslStep::Failure(SaslError::Malformed),
+        };
+        let (username, digest) = match text.rsplit_once(' ') {
+            Some(parts) => parts,
+            None => return SaslStep::Failure(SaslError::Malformed),
+        };
+        let secret = match self.context.authenticator.secret(username) {
+            Some(secret) => secret,
+            None => return SaslStep::Failure(SaslError::Failed),
+        };
+        let expected = hex(&hmac_md5(secret.as_bytes(), challenge.as_bytes()));
+        if constant_time::verify_slices_are_equal(
+            expected.as_bytes(),
+            digest.to_ascii_lowercase().as_bytes(),
+        )
+        .is_err()
+        {
+            return SaslStep::Failure(SaslError::Failed);
+        }
+        finish(&self.context, "", username)
+    }
+}
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..16].copy_from_slice(&Md5::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Md5::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Md5::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn nonce_u64() -> u64 {
+    let mut data = [0u8; 8];
+    let _ = rand::fill(&mut data);
+    u64::from_be_bytes(data)
+}
+
+// Salt for an unknown user, the same on every attempt so it cannot be
+// told apart from a real one.
+fn fake_salt(authcid: &str) -> Vec<u8> {
+    static KEY: OnceLock<hmac::Key> = OnceLock::new();
+    let key = KEY.get_or_init(|| {
+        let mut secret = [0u8; digest::SHA256_OUTPUT_LEN];
+        let _ = rand::fill(&mut secret);
+        hmac::Key::new(hmac::HMAC_SHA256, &secret)
+    });
+    hmac::sign(key, authcid.as_bytes()).as_ref()[..16].to_vec()
+}
+
+enum ScramState {
+    Start,
+    First {
+        gs2_header: String,
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+        authzid: String,
+        authcid: String,
+        credentials: Option<ScramCredentials>,
+    },
+    Final(String),
+    Done,
+}
+
+// SCRAM-SHA-256 (RFC 5802, RFC 7677), -PLUS binds to tls-exporter.
+struct Scram {
+    context: SaslContext,
+    plus: bool,
+    state: ScramState,
+}
+
+impl Scram {
+    fn new(context: SaslContext, plus: bool) -> Self {
+        Scram {
+            context,
+            plus,
+            state: ScramState::Start,
+        }
+    }
+
+    fn client_first(&mut self, text: &str) -> Result<Vec<u8>, SaslError> {
+        let (flag, rest) = text.split_once(',').ok_or(SaslError::Malformed)?;
+        let (gs2_authzid, bare) = rest.split_once(',').ok_or(SaslError::Malformed)?;
+        match (flag, self.plus) {
+            ("n", false) => {}
+            // The client could bind but believes we can't: a downgrade.
+            ("y", false) if self.context.plus => return Err(SaslError::Failed),
+            ("y", false) => {}
+            ("p=tls-exporter", true) => {}
+            _ => return Err(SaslError::Malformed),
+        }
+        let authzid = match gs2_authzid.strip_prefix("a=") {
+            Some(name) => decode_saslname(name)?,
+            None if gs2_authzid.is_empty() => String::new(),
+            None => return Err(SaslError::Malformed),
+        };
+        let mut attrs = bare.split(',');
+        let authcid = attrs
+            .next()
+            .and_then(|attr| attr.strip_prefix("n="))
+            .ok_or(SaslError::Malformed)?;
+        let authcid = decode_saslname(authcid)?;
+        let client_nonce = attrs
+            .next()
+            .and_then(|attr| attr.strip_prefix("r="))
+            .filter(|nonce| !nonce.is_empty())
+            .ok_or(SaslError::Malformed)?;
+        let mut server_nonce = [0u8; 18];
+        rand::fill(&mut server_nonce).map_err(|_| SaslError::Failed)?;
+        let nonce = format!("{}{}", client_nonce, STANDARD.encode(server_nonce));
+        // Unknown users get made up parameters and fail at the proof.
+        let credentials = self.context.authenticator.scram_sha256(&authcid);
+        let (salt, iterations) = match &credentials {
+            Some(credentials) => (credentials.salt.clone(), credentials.iterations),
+            None => (fake_salt(&authcid), constants::SMTPD_SCRAM_ITERATIONS),
+        };
+        let server_first = format!("r={},s={},i={}", nonce, STANDARD.encode(salt), iterations);
+        self.state = ScramState::First {
+            gs2_header: format!("{},{},", flag, gs2_authzid),
+            client_first_bare: bare.to_string(),
+            server_first: server_first.clone(),
+            nonce,
+            authzid,
+            authcid,
+            credentials,
+        };
+        Ok(server_first.into_bytes())
+    }
+
+    fn client_final(&mut self, text: &str) -> Result<Vec<u8>, SaslError> {
+        let ScramState::First {
+            gs2_header,
+            client_first_bare,
+            server_first,
+            nonce,
+            authzid,
+            authcid,
+            credentials,
+        } = std::mem::replace(&mut self.state, ScramState::Done)
+        else {
+            return Err(SaslError::Malformed);
+        };
+        let (without_proof, proof) = text.rsplit_once(",p=").ok_or(SaslError::Malformed)?;
+        let mut attrs = without_proof.split(',');
+        let binding = attrs
+            .next()
+            .and_then(|attr| attr.strip_prefix("c="))
+            .ok_or(SaslError::Malformed)?;
+        let client_nonce = attrs
+            .next()
+            .and_then(|attr| attr.strip_prefix("r="))
+            .ok_or(SaslError::Malformed)?;
+        let mut expected = gs2_header.into_bytes();
+        if self.plus {
+            expected.extend_from_slice(self.context.binding.as_deref().unwrap_or_default());
+        }
+        if binding != STANDARD.encode(&expected) || client_nonce != nonce {
+            return Err(SaslError::Failed);
+        }
+        let proof = STANDARD.decode(proof).map_err(|_| SaslError::Malformed)?;
+        let credentials = credentials.ok_or(SaslError::Failed)?;
+        let message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let stored_key = hmac::Key::new(hmac::HMAC_SHA256, &credentials.stored_key);
+        let signature = hmac::sign(&stored_key, message.as_bytes());
+        if proof.len() != signature.as_ref().len() {
+            return Err(SaslError::Failed);
+        }
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(signature.as_ref())
+            .map(|(a, b)| a ^ b)
+            .collect();
+        let check = digest::digest(&digest::SHA256, &client_key);
+        constant_time::verify_slices_are_equal(check.as_ref(), &credentials.stored_key)
+            .map_err(|_| SaslError::Failed)?;
+        let identity = match finish(&self.context, &authzid, &authcid) {
+            SaslStep::Success(identity) => identity,
+            _ => return Err(SaslError::Failed),
+        };
+        let server_key = hmac::Key::new(hmac::HMAC_SHA256, &credentials.server_key);
+        let verifier = hmac::sign(&server_key, message.as_bytes());
+        self.state = ScramState::Final(identity);
+        Ok(format!("v={}", STANDARD.encode(verifier.as_ref())).into_bytes())
+    }
+}
+
+impl SaslMechanism for Scram {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let response = match (&self.state, response) {
+            (ScramState::Start, None) => return SaslStep::Challenge(Vec::new()),
+            (_, Some(response)) => response,
+            _ => return SaslStep::Failure(SaslError::Malformed),
+        };
+        let text = match std::str::from_utf8(response) {
+            Ok(text) => text,
+            Err(_) => return SaslStep::Failure(SaslError::Malformed),
+        };
+        let result = match &self.state {
+            ScramState::Start => self.client_first(text),
+            ScramState::First { .. } => self.client_final(text),
+            ScramState::Final(identity) if text.is_empty() => {
+                return SaslStep::Success(identity.clone());
+            }
+            _ => Err(SaslError::Malformed),
+        };
+        match result {
+            Ok(challenge) => SaslStep::Challenge(challenge),
+            Err(error) => SaslStep::Failure(error),
+        }
+    }
+}
+
+fn decode_saslname(name: &str) -> Result<String, SaslError> {
+    let mut out = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(pos) = rest.find('=') {
+        out.push_str(&rest[..pos]);
+        match rest.get(pos + 1..pos + 3) {
+            Some("2C") => out.push(','),
+            Some("3D") => out.push('='),
+            _ => return Err(SaslError::Malformed),
+        }
+        rest = &rest[pos + 3..];
+    }
+    out.push_str(rest);
+    if out.is_empty() {
+        return Err(SaslError::Malformed);
+    }
+    Ok(out)
+}
@@ -5,10 +5,11 @@
 // src/smtpd/server.rs
 // Server of SMTPd.
 
-use super::session;
-use crate::conf;
+use super::{auth, cert, session, stream};
+use crate::{conf, delivery, directory, queue};
 use std::sync::Arc;
 use tokio::{net::TcpListener, sync::watch};
+use tokio_rustls::TlsAcceptor;
 
 #[derive(Debug)]
 pub enum SmtpServerControl {
@@ -16,50 +17,135 @@ pub enum SmtpServerControl {
     Shutdown,
 }
 
-#[derive(Debug)]
+pub struct SmtpListener {
+    listener: TcpListener,
+    implicit_tls: bool,
+    protocol: session::SmtpProtocol,
+}
+
 pub struct SmtpServer {
-    listeners: Vec<TcpListener>,
+    listeners: Vec<SmtpListener>,
     config: Arc<conf::ConfigSmtpServer>,
+    acceptor: Option<cert::SmtpAcceptor>,
+    authenticator: Option<Arc<dyn auth::SmtpAuthenticator>>,
+    directory: Arc<directory::Directory>,
+    queue: Arc<queue::Queue>,
+    reload: Option<watch::Sender<TlsAcceptor>>,
     control: watch::Sender<SmtpServerControl>,
 }
 
 impl SmtpServer {
-    pub fn new(len: usize, config: Arc<conf::ConfigSmtpServer>) -> anyhow::Result<Self> {
+    pub fn new(
+        len: usize,
+        config: Arc<conf::ConfigSmtpServer>,
+        queue: Arc<queue::Queue>,
+    ) -> anyhow::Result<Self> {
         let listeners = Vec::with_capacity(len);
         let (control, _) = watch::channel(SmtpServerControl::Initialize);
+        let (acceptor, reload) = match &config.tls {
+            Some(tls) => {
+                // Serve without TLS rather than not at all.
+                match cert::SmtpAcceptor::new(&config.domain, tls) {
+                    Ok((acceptor, reload)) => (Some(acceptor), Some(reload)),
+                    Err(e) => {
+                        eprintln!("{}: TLS disabled: {}", config.domain, e);
+                        (None, None)
+                    }
+                }
+            }
+            None => (None, None),
+        };
+        let authenticator =
+            match &config.auth {
+                Some(auth) => Some(Arc::new(auth::ConfigAuthenticator::new(auth)?)
+                    as Arc<dyn auth::SmtpAuthenticator>),
+                None => None,
+            };
+        let directory = Arc::new(directory::Directory::new(&config.directory));
         Ok(Self {
             listeners,
             control,
             config,
+            acceptor,
+            authenticator,
+            directory,
+            queue,
+            reload,
         })
     }
 
-    pub async fn add(&mut self, addr: &str) -> anyhow::Result<()> {
-        self.listeners.push(TcpListener::bind(addr).await?);
+    pub fn directory(&self) -> Arc<directory::Directory> {
+        self.directory.clone()
+    }
+
+    // Implicit TLS listeners (RFC 8314) handshake before the greeting.
+    pub async fn add(
+        &mut self,
+        addr: &str,
+        implicit_tls: bool,
+        protocol: session::SmtpProtocol,
+    ) -> anyhow::Result<()> {
+        if implicit_tls && self.acceptor.is_none() {
+            eprintln!("{}: no TLS, not listening on {}", self.config.domain, addr);
+            return Ok(());
+        }
+        if matches!(protocol, session::SmtpProtocol::Lmtp) && self.config.delivery.is_none() {
+            return Err(anyhow::anyhow!("LMTP on {} requires delivery config", addr));
+        }
+        self.listeners.push(SmtpListener {
+            listener: TcpListener::bind(addr).await?,
+            implicit_tls,
+            protocol,
+        });
         Ok(())
     }
 
-    pub fn run(self) -> watch::Sender<SmtpServerControl> {
-        for listener in self.listeners {
+    pub fn run(self, delivery: Arc<delivery::DeliveryAgent>) -> watch::Sender<SmtpServerControl> {
+        if let Some(reload) = self.reload {
+            let config = self.config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = cert::reload(config.clone(), reload).await {
+                    eprintln!("{}: TLS reload stopped: {}", config.domain, e);
+                }
+            });
+        }
+        for SmtpListener {
+            listener,
+            implicit_tls,
+            protocol,
+        } in self.listeners
+        {
             let mut rx = self.control.subscribe();
             let config = self.config.clone();
+            let context = Arc::new(session::SmtpContext {
+                acceptor: self.acceptor.clone(),
+                authenticator: self.authenticator.clone(),
+                directory: self.directory.clone(),
+                queue: self.queue.clone(),
+                delivery: delivery.clone(),
+                protocol,
+            });
             tokio::spawn(async move {
                 loop {
                     tokio::select! {
                         control = rx.changed() => {
-                            match control {
-                                _Initialize => {}
-                                _Shutdown => {
-                                    break;
-                                }
+                            if control.is_err() || matches!(*rx.borrow(), SmtpServerControl::Shutdown) {
+                                break;
                             }
                         }
                         res = listener.accept() => {
                             match res {
                                 Ok((stream, addr)) => {
-                                    let session: session::SmtpSession = session::SmtpSession::new(config.clone(), addr, stream);
-                                    tokio::spawn( async move {
-                                        let _ = session.run().await;
+                                    let config = config.clone();
+                                    let context = context.clone();
+                                    tokio::spawn(async move {
+                                        let tx_len = config.expand.pipe_tx_len;
+                                        let rx_len = config.expand.pipe_rx_len;
+                                        let stream = match (&context.acceptor, implicit_tls) {
+                                            (Some(acceptor), true) => stream::common::SmtpStream::accept(&acceptor.get(), stream, tx_len, rx_len).await?,
+                                            _ => stream::common::SmtpStream::new(stream, tx_len, rx_len),
+                                        };
+                                        session::SmtpSession::new(config, context, addr, stream).run().await
                                     });
                                 }
                                 Err(_e) => {
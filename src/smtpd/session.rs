@@ -5,12 +5,11 @@
 // src/smtpd/session.rs
 // Session of SMTPd.
 
-use super::{allow, cmd, stream, util};
+use super::{allow, auth, cert, cmd, envelope, stream, util};
 use crate::smtpd::stream::common::SmtpStreamTrait;
-use crate::{conf, constants};
+use crate::{conf, constants, delivery, directory, queue};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
 
 pub enum SmtpSessionStatus {
     Start,
@@ -19,29 +18,117 @@ pub enum SmtpSessionStatus {
     Sender,
     Rcpt,
     Data,
+    Chunk,
     Stop,
 }
 
+#[derive(Clone, Copy)]
+pub enum SmtpProtocol {
+    Smtp,
+    Lmtp,
+}
+
+impl SmtpProtocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SmtpProtocol::Smtp => constants::SMTPD_INFO,
+            SmtpProtocol::Lmtp => constants::LMTPD_INFO,
+        }
+    }
+
+    // Commands that open the session, LMTP replaces both with LHLO.
+    pub fn hello(&self) -> &'static [&'static str] {
+        match self {
+            SmtpProtocol::Smtp => &["HELO", "EHLO"],
+            SmtpProtocol::Lmtp => &["LHLO"],
+        }
+    }
+
+    // DSN, FUTURERELEASE, DELIVERBY and MT-PRIORITY act on queued mail,
+    // LMTP delivers before replying and has no queue to act on.
+    pub fn queued(&self) -> bool {
+        matches!(self, SmtpProtocol::Smtp)
+    }
+}
+
+// Shared by every session accepted on one listener.
+pub struct SmtpContext {
+    pub acceptor: Option<cert::SmtpAcceptor>,
+    pub authenticator: Option<Arc<dyn auth::SmtpAuthenticator>>,
+    pub directory: Arc<directory::Directory>,
+    pub queue: Arc<queue::Queue>,
+    pub delivery: Arc<delivery::DeliveryAgent>,
+    pub protocol: SmtpProtocol,
+}
+
 pub struct SmtpSession {
     pub config: Arc<conf::ConfigSmtpServer>,
+    pub context: Arc<SmtpContext>,
     pub addr: SocketAddr,
     pub stream: stream::common::SmtpStream,
     pub status: SmtpSessionStatus,
     pub tls: bool,
+    pub domain: String,
+    pub esmtp: bool,
     pub client: String,
+    pub user: Option<String>,
+    pub envelope: Option<envelope::SmtpEnvelope>,
+    pub body: Vec<u8>,
 }
 
 impl SmtpSession {
-    pub fn new(config: Arc<conf::ConfigSmtpServer>, addr: SocketAddr, stream: TcpStream) -> Self {
-        let tx_len = config.expand.pipe_tx_len;
-        let rx_len = config.expand.pipe_rx_len;
-        SmtpSession {
+    pub fn new(
+        config: Arc<conf::ConfigSmtpServer>,
+        context: Arc<SmtpContext>,
+        addr: SocketAddr,
+        stream: stream::common::SmtpStream,
+    ) -> Self {
+        let mut session = SmtpSession {
+            domain: config.domain.clone(),
             config,
+            context,
             addr,
-            stream: stream::common::SmtpStream::new(stream, tx_len, rx_len),
+            tls: stream.is_tls(),
+            stream,
             status: SmtpSessionStatus::Start,
-            tls: false,
+            esmtp: false,
             client: String::new(),
+            user: None,
+            envelope: None,
+            body: Vec::new(),
+        };
+        session.update_domain();
+        session
+    }
+
+    // Greet with the SNI name when it is one of the configured domains.
+    pub fn update_domain(&mut self) {
+        let tls = match &self.config.tls {
+            Some(tls) => tls,
+            None => return,
+        };
+        if let Some(name) = self
+            .stream
+            .server_name()
+            .and_then(|name| cert::server_name(&self.config.domain, tls, name))
+        {
+            self.domain = name;
+        }
+    }
+
+    // Replies owed at the end of the data, one per recipient in LMTP.
+    pub fn replies(&self) -> usize {
+        match (self.context.protocol, &self.envelope) {
+            (SmtpProtocol::Lmtp, Some(envelope)) => envelope.forward_paths.len().max(1),
+            _ => 1,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = None;
+        self.body = Vec::new();
+        if !matches!(self.status, SmtpSessionStatus::Init) {
+            self.status = SmtpSessionStatus::Hello;
         }
     }
 
@@ -49,33 +136,60 @@ impl SmtpSession {
         while !matches!(self.status, SmtpSessionStatus::Stop) {
             if matches!(self.status, SmtpSessionStatus::Start) {
                 let hello = format!(
-                    "220 {} {} {}\r\n",
-                    self.config.domain,
-                    constants::SMTPD_INFO,
+                    "{} {} {}",
+                    self.domain,
+                    self.context.protocol.name(),
                     constants::SMTPD_NAME
                 );
-                self.stream.get_writer().send(hello).await?;
+                allow::SmtpError::ServiceReady
+                    .return_text(self.stream.get_writer(), &hello)
+                    .await?;
                 self.status = SmtpSessionStatus::Init;
             }
-            let mut mes = match self.stream.read_line().await {
-                Some(s) => s,
+            let mut mes = match self.stream.read().await {
+                Some(stream::common::SmtpStreamRead::Line(s)) => s,
+                Some(stream::common::SmtpStreamRead::Error(error)) => {
+                    error.reply().return_code(self.stream.get_writer()).await?;
+                    continue;
+                }
+                Some(_) => continue,
                 None => break,
             };
             util::remove_crlf(&mut mes);
-            let para: Vec<&str> = mes.split_whitespace().collect();
-            if mes.is_empty() {
-                allow::SmtpError::new(500)
+            let command = match mes.split_whitespace().next() {
+                Some(command) => command.to_ascii_uppercase(),
+                None => {
+                    allow::SmtpError::SyntaxError
+                        .return_code(self.stream.get_writer())
+                        .await?;
+                    continue;
+                }
+            };
+            if !mes.is_ascii() && !matches!(command.as_str(), "MAIL" | "RCPT") {
+                allow::SmtpError::InvalidCharacter
                     .return_code(self.stream.get_writer())
                     .await?;
                 continue;
             }
+            let mut para: Vec<&str> = mes.split_whitespace().collect();
+            para[0] = &command;
+            if let Err(error) = cmd::lookup(&self, para[0]) {
+                error.return_code(self.stream.get_writer()).await?;
+                continue;
+            }
             if cmd::global_command(&mut self, &para).await? {
                 continue;
             }
-            allow::check_command(&para[0])
+            if allow::check_status(para[0], &self.status)
+                && cmd::mail_command(&mut self, &para).await?
+            {
+                continue;
+            }
+            allow::check_command(para[0])
                 .return_code(self.stream.get_writer())
                 .await?;
         }
-        Ok(())
+        // Flush pending replies and close, with close_notify under TLS.
+        self.stream.stop().await
     }
 }
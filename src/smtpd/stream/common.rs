@@ -4,17 +4,20 @@
 // src/smtpd/stream/common.rs
 // Common method of of SMTPd stream.
 
-use super::{tcp, util};
+use super::{tcp, tls, util};
+use crate::constants;
+use crate::smtpd::allow;
 use std::marker::{Send, Unpin};
-use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::{mpsc, watch};
 use tokio::task;
-use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ProtocolVersion;
 
 pub enum SmtpStream {
     Tcp(tcp::TcpSmtpStream),
-    //Tls(TlsSmtpStream),
+    Tls(tls::TlsSmtpStream),
 }
 
 impl SmtpStream {
@@ -28,64 +31,221 @@ impl SmtpStream {
         })
     }
 
-    //pub fn starttls() -> Self {}
+    // Hand the socket over to TLS. Pre-TLS input still queued is
+    // discarded so it can't be replayed inside the encrypted session.
+    pub async fn starttls(
+        &mut self,
+        acceptor: &TlsAcceptor,
+        write_size: usize,
+        read_size: usize,
+    ) -> anyhow::Result<()> {
+        let stream = match self {
+            SmtpStream::Tcp(stream) => stream.take().await?,
+            SmtpStream::Tls(_) => return Err(anyhow::anyhow!("TLS already active")),
+        };
+        *self = Self::accept(acceptor, stream, write_size, read_size).await?;
+        Ok(())
+    }
+
+    pub async fn accept(
+        acceptor: &TlsAcceptor,
+        stream: TcpStream,
+        write_size: usize,
+        read_size: usize,
+    ) -> anyhow::Result<Self> {
+        let stream = acceptor.accept(stream).await?;
+        let (_, conn) = stream.get_ref();
+        let server_name = conn.server_name().map(String::from);
+        // RFC 9266: tls-exporter is only defined for TLS 1.3 here.
+        let binding = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_3) => conn
+                .export_keying_material([0u8; 32], b"EXPORTER-Channel-Binding", None)
+                .ok()
+                .map(|key| key.to_vec()),
+            _ => None,
+        };
+        let (handler, read_rx, write_tx, control) = new(stream, write_size, read_size);
+        Ok(SmtpStream::Tls(tls::TlsSmtpStream {
+            server_name,
+            binding,
+            write_tx,
+            read_rx,
+            control,
+            handler: Some(handler),
+        }))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, SmtpStream::Tls(_))
+    }
+
+    pub fn channel_binding(&self) -> Option<&[u8]> {
+        match self {
+            SmtpStream::Tcp(_) => None,
+            SmtpStream::Tls(stream) => stream.binding.as_deref(),
+        }
+    }
+
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            SmtpStream::Tcp(_) => None,
+            SmtpStream::Tls(stream) => stream.server_name.as_deref(),
+        }
+    }
 }
 
 impl SmtpStreamTrait for SmtpStream {
     async fn stop(&mut self) -> anyhow::Result<()> {
         match self {
             SmtpStream::Tcp(stream) => stream.stop().await,
+            SmtpStream::Tls(stream) => stream.stop().await,
         }
     }
 
-    fn clear(&mut self) {
+    fn start_utf8(&mut self) {
         match self {
-            SmtpStream::Tcp(stream) => stream.clear(),
+            SmtpStream::Tcp(stream) => stream.start_utf8(),
+            SmtpStream::Tls(stream) => stream.start_utf8(),
         }
     }
 
-    async fn read_line(&mut self) -> Option<String> {
+    fn start_data(&mut self) {
         match self {
-            SmtpStream::Tcp(stream) => stream.read_line().await,
+            SmtpStream::Tcp(stream) => stream.start_data(),
+            SmtpStream::Tls(stream) => stream.start_data(),
+        }
+    }
+
+    fn start_auth(&mut self) {
+        match self {
+            SmtpStream::Tcp(stream) => stream.start_auth(),
+            SmtpStream::Tls(stream) => stream.start_auth(),
+        }
+    }
+
+    async fn read(&mut self) -> Option<SmtpStreamRead> {
+        match self {
+            SmtpStream::Tcp(stream) => stream.read().await,
+            SmtpStream::Tls(stream) => stream.read().await,
+        }
+    }
+
+    fn pending(&self) -> bool {
+        match self {
+            SmtpStream::Tcp(stream) => stream.pending(),
+            SmtpStream::Tls(stream) => stream.pending(),
         }
     }
 
     fn get_writer(&mut self) -> mpsc::Sender<String> {
         match self {
-            SmtpStream::Tcp(stream) => stream.write_tx.clone(),
+            SmtpStream::Tcp(stream) => stream.get_writer(),
+            SmtpStream::Tls(stream) => stream.get_writer(),
         }
     }
 }
 
 pub trait SmtpStreamTrait {
     async fn stop(&mut self) -> anyhow::Result<()>;
-    fn clear(&mut self);
-    async fn read_line(&mut self) -> Option<String>;
+    fn start_utf8(&mut self);
+    fn start_data(&mut self);
+    fn start_auth(&mut self);
+    async fn read(&mut self) -> Option<SmtpStreamRead>;
+    fn pending(&self) -> bool;
     fn get_writer(&mut self) -> mpsc::Sender<String>;
 }
 
+#[derive(Clone)]
 pub enum SmtpStreamControl {
     Initialize,
+    Utf8,
+    Data,
+    Auth,
     ShutdownReady,
     Shutdown,
 }
 
+pub enum SmtpStreamError {
+    TooLong,
+    InvalidCharacter,
+    NotAscii,
+}
+
+impl SmtpStreamError {
+    pub fn reply(&self) -> allow::SmtpError {
+        match self {
+            SmtpStreamError::TooLong => allow::SmtpError::LineTooLong,
+            SmtpStreamError::InvalidCharacter => allow::SmtpError::InvalidCharacter,
+            SmtpStreamError::NotAscii => allow::SmtpError::NonAsciiAddress,
+        }
+    }
+}
+
+// Errors are passed up in input order, so their replies stay in
+// sequence with the pipelined commands around them.
+pub enum SmtpStreamRead {
+    Line(String),
+    Chunk(Vec<u8>),
+    DataEnd(Option<SmtpStreamError>),
+    Error(SmtpStreamError),
+}
+
+#[derive(Clone, Copy)]
+enum SmtpStreamMode {
+    Command,
+    Data,
+    Bdat(usize),
+}
+
+// Parse "BDAT <size> [LAST]". A valid BDAT line is always followed by
+// exactly size octets, whether the command is accepted or not.
+pub fn parse_bdat(para: &[&str]) -> Option<(usize, bool)> {
+    if para.len() < 2 || para.len() > 3 || !para[0].eq_ignore_ascii_case("BDAT") {
+        return None;
+    }
+    if !para[1].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let size = para[1].parse().ok()?;
+    match para.get(2) {
+        Some(last) if last.eq_ignore_ascii_case("LAST") => Some((size, true)),
+        Some(_) => None,
+        None => Some((size, false)),
+    }
+}
+
+fn starts_with_verb(line: &[u8], verb: &[u8]) -> bool {
+    line.get(..verb.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(verb))
+}
+
+// Only MAIL and RCPT carry addresses, 8-bit elsewhere is just invalid.
+fn address_command(line: &[u8]) -> bool {
+    starts_with_verb(line, b"MAIL") || starts_with_verb(line, b"RCPT")
+}
+
+// RFC 4954 4: AUTH commands and SASL responses may run to 12288 octets,
+// every other command keeps the RFC 5321 limit.
+fn too_long(line: &[u8], sasl: bool) -> bool {
+    line.len() > constants::SMTPD_LINE_LIMIT && !sasl && !starts_with_verb(line, b"AUTH ")
+}
+
 pub fn new<S>(
     stream: S,
     write_size: usize,
     read_size: usize,
 ) -> (
     task::JoinHandle<anyhow::Result<S>>,
-    mpsc::Receiver<String>,
+    mpsc::Receiver<SmtpStreamRead>,
     mpsc::Sender<String>,
     watch::Sender<SmtpStreamControl>,
 )
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let (write_tx, mut write_rx) = mpsc::channel::<String>(write_size);
-    let (read_tx, read_rx) = mpsc::channel::<String>(read_size);
-    let (control, mut answer) = watch::channel(SmtpStreamControl::Initialize);
+    let (write_tx, write_rx) = mpsc::channel::<String>(write_size);
+    let (read_tx, read_rx) = mpsc::channel::<SmtpStreamRead>(read_size);
+    let (control, answer) = watch::channel(SmtpStreamControl::Initialize);
     let handler: task::JoinHandle<anyhow::Result<S>> =
         tokio::spawn(async move { create::<S>(stream, write_rx, read_tx, answer).await });
     (handler, read_rx, write_tx, control)
@@ -94,7 +254,7 @@ where
 pub async fn create<S>(
     stream: S,
     mut write_rx: mpsc::Receiver<String>,
-    read_tx: mpsc::Sender<String>,
+    read_tx: mpsc::Sender<SmtpStreamRead>,
     mut answer: watch::Receiver<SmtpStreamControl>,
 ) -> anyhow::Result<S>
 where
@@ -102,23 +262,40 @@ where
 {
     let mut reader = BufReader::new(stream);
     let mut line = Vec::<u8>::new();
+    let mut mode = SmtpStreamMode::Command;
+    let mut chunk = Vec::<u8>::new();
+    let mut data_error: Option<SmtpStreamError> = None;
+    let mut out = Vec::<u8>::new();
+    let mut utf8 = false;
+    let mut sasl = false;
     loop {
+        let (eight_bit, limit) = match mode {
+            SmtpStreamMode::Data => (true, constants::SMTPD_TEXT_LIMIT),
+            _ => (utf8, constants::SMTPD_AUTH_LINE_LIMIT),
+        };
         tokio::select! {
+            biased;
             ctrl = answer.changed() => {
-                match ctrl {
-                    Initialize => {
-                        continue;
+                if ctrl.is_err() {
+                    util::write_flush(reader.get_mut(), &mut out).await?;
+                    util::clear_writer(reader.get_mut(), write_rx).await?;
+                    return Ok(reader.into_inner());
+                }
+                let control = answer.borrow_and_update().clone();
+                match control {
+                    SmtpStreamControl::Initialize | SmtpStreamControl::ShutdownReady => continue,
+                    SmtpStreamControl::Utf8 => {
+                        utf8 = true;
                     }
-                    ShutdownReady => {
-                        match answer.changed() {
-                            Shutdown => {
-                                util::clear_writer(reader.get_mut(), write_rx).await?;
-                                return Ok(reader.into_inner());
-                            }
-                            _ => continue,
-                        }
+                    SmtpStreamControl::Data => {
+                        mode = SmtpStreamMode::Data;
+                    }
+                    // The next line answers a challenge.
+                    SmtpStreamControl::Auth => {
+                        sasl = true;
                     }
-                    Shutdown => {
+                    SmtpStreamControl::Shutdown => {
+                        util::write_flush(reader.get_mut(), &mut out).await?;
                         util::clear_writer(reader.get_mut(), write_rx).await?;
                         return Ok(reader.into_inner());
                     }
@@ -127,31 +304,91 @@ where
             message = write_rx.recv() => {
                 let mes = match message {
                     Some(s) => s,
-                    None => return Ok(reader.into_inner()),
+                    None => {
+                        util::write_flush(reader.get_mut(), &mut out).await?;
+                        return Ok(reader.into_inner());
+                    }
                 };
-                reader.get_mut().write_all(mes.as_bytes()).await.map_err(anyhow::Error::from)?;
+                out.extend_from_slice(mes.as_bytes());
+                while let Ok(mes) = write_rx.try_recv() {
+                    out.extend_from_slice(mes.as_bytes());
+                }
             }
-            res = util::read_line_limit(&mut reader, &mut line, false, 512) => {
-                match res {
-                    Ok(len) => {
-                        if len == 0 {
-                            return Ok(reader.into_inner());
+            res = async {
+                match mode {
+                    SmtpStreamMode::Bdat(remaining) => util::read_count(&mut reader, &mut chunk, remaining).await,
+                    _ => util::read_line_limit(&mut reader, &mut line, eight_bit, limit).await,
+                }
+            } => {
+                match (mode, res) {
+                    (_, Ok(0)) => return Ok(reader.into_inner()),
+                    (SmtpStreamMode::Bdat(remaining), Ok(len)) => {
+                        if remaining == len {
+                            if !chunk.is_empty() {
+                                read_tx.send(SmtpStreamRead::Chunk(std::mem::take(&mut chunk))).await.map_err(anyhow::Error::from)?;
+                            }
+                            read_tx.send(SmtpStreamRead::DataEnd(None)).await.map_err(anyhow::Error::from)?;
+                            mode = SmtpStreamMode::Command;
+                        } else {
+                            if chunk.len() >= constants::SMTPD_DATA_CHUNK {
+                                read_tx.send(SmtpStreamRead::Chunk(std::mem::take(&mut chunk))).await.map_err(anyhow::Error::from)?;
+                            }
+                            mode = SmtpStreamMode::Bdat(remaining - len);
                         }
-                        match String::from_utf8(line) {
-                            Ok(mes) => read_tx.send(mes).await.map_err(anyhow::Error::from)?,
-                            Err(_) => reader.get_mut().write_all(b"501 5.5.2 Unrecognized code").await.map_err(anyhow::Error::from)?,
+                    }
+                    (SmtpStreamMode::Command, Ok(_)) if too_long(&line, std::mem::take(&mut sasl)) => {
+                        read_tx.send(SmtpStreamRead::Error(SmtpStreamError::TooLong)).await.map_err(anyhow::Error::from)?;
+                    }
+                    (SmtpStreamMode::Command, Ok(_)) => {
+                        match String::from_utf8(std::mem::take(&mut line)) {
+                            Ok(mes) => {
+                                let para: Vec<&str> = mes.split_whitespace().collect();
+                                let bdat = parse_bdat(&para);
+                                read_tx.send(SmtpStreamRead::Line(mes)).await.map_err(anyhow::Error::from)?;
+                                match bdat {
+                                    Some((0, _)) => read_tx.send(SmtpStreamRead::DataEnd(None)).await.map_err(anyhow::Error::from)?,
+                                    Some((size, _)) => mode = SmtpStreamMode::Bdat(size),
+                                    None => {}
+                                }
+                            }
+                            Err(_) => read_tx.send(SmtpStreamRead::Error(SmtpStreamError::InvalidCharacter)).await.map_err(anyhow::Error::from)?,
                         };
                     }
-                    Err(e) => {
-                        match e {
-                            util::UtilReadError::TooLong => reader.get_mut().write_all(b"501 5.5.2 The line is too long").await.map_err(anyhow::Error::from)?,
-                            util::UtilReadError::NotAscii => reader.get_mut().write_all(b"501 5.5.2 Not ASCII").await.map_err(anyhow::Error::from)?,
+                    (SmtpStreamMode::Data, Ok(_)) => {
+                        if line == b".\r\n" {
+                            if !chunk.is_empty() {
+                                read_tx.send(SmtpStreamRead::Chunk(std::mem::take(&mut chunk))).await.map_err(anyhow::Error::from)?;
+                            }
+                            read_tx.send(SmtpStreamRead::DataEnd(data_error.take())).await.map_err(anyhow::Error::from)?;
+                            mode = SmtpStreamMode::Command;
+                        } else {
+                            let start = usize::from(line.first() == Some(&b'.'));
+                            chunk.extend_from_slice(&line[start..]);
+                            if chunk.len() >= constants::SMTPD_DATA_CHUNK {
+                                read_tx.send(SmtpStreamRead::Chunk(std::mem::take(&mut chunk))).await.map_err(anyhow::Error::from)?;
+                            }
+                        }
+                    }
+                    (_, Err(util::UtilReadError::NetError(e))) => return Err(e.into()),
+                    (SmtpStreamMode::Data, Err(_)) => {
+                        data_error = Some(SmtpStreamError::TooLong);
+                    }
+                    (_, Err(e)) => {
+                        sasl = false;
+                        let error = match e {
+                            util::UtilReadError::TooLong => SmtpStreamError::TooLong,
+                            util::UtilReadError::NotAscii if address_command(&line) => SmtpStreamError::NotAscii,
+                            util::UtilReadError::NotAscii => SmtpStreamError::InvalidCharacter,
                             util::UtilReadError::NetError(e) => return Err(e.into()),
                         };
+                        read_tx.send(SmtpStreamRead::Error(error)).await.map_err(anyhow::Error::from)?;
                     }
                 };
-                line = Vec::<u8>::new();
+                line.clear();
             }
         }
+        if !util::check_pending(&reader, &read_tx) {
+            util::write_flush(reader.get_mut(), &mut out).await?;
+        }
     }
 }
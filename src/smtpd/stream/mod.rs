@@ -7,5 +7,5 @@
 
 pub mod common;
 mod tcp;
-//mod tls;
-mod util;
+mod tls;
+pub mod util;
@@ -12,30 +12,52 @@ use tokio::task;
 
 pub struct TcpSmtpStream {
     pub write_tx: mpsc::Sender<String>,
-    pub read_rx: mpsc::Receiver<String>,
+    pub read_rx: mpsc::Receiver<common::SmtpStreamRead>,
     pub control: watch::Sender<common::SmtpStreamControl>,
     pub handler: Option<task::JoinHandle<anyhow::Result<TcpStream>>>,
 }
 
-impl common::SmtpStreamTrait for TcpSmtpStream {
-    async fn stop(&mut self) -> anyhow::Result<()> {
+impl TcpSmtpStream {
+    // Stop the stream task and take the socket back, anything it had
+    // buffered but not yet handed to the session is dropped with it.
+    pub async fn take(&mut self) -> anyhow::Result<TcpStream> {
         let _ = self.control.send(common::SmtpStreamControl::Shutdown);
-        let stream = match self.handler.take() {
+        match self.handler.take() {
             Some(handler) => handler.await?,
-            None => return Ok(()),
-        };
-        let _ = stream?.shutdown().await;
+            None => Err(anyhow::anyhow!("stream already stopped")),
+        }
+    }
+}
+
+impl common::SmtpStreamTrait for TcpSmtpStream {
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        if self.handler.is_none() {
+            return Ok(());
+        }
+        let _ = self.take().await?.shutdown().await;
         Ok(())
     }
 
-    fn clear(&mut self) {
-        while let Ok(_mes) = self.read_rx.try_recv() {}
+    fn start_utf8(&mut self) {
+        let _ = self.control.send(common::SmtpStreamControl::Utf8);
     }
 
-    async fn read_line(&mut self) -> Option<String> {
+    fn start_data(&mut self) {
+        let _ = self.control.send(common::SmtpStreamControl::Data);
+    }
+
+    fn start_auth(&mut self) {
+        let _ = self.control.send(common::SmtpStreamControl::Auth);
+    }
+
+    async fn read(&mut self) -> Option<common::SmtpStreamRead> {
         self.read_rx.recv().await
     }
 
+    fn pending(&self) -> bool {
+        !self.read_rx.is_empty()
+    }
+
     fn get_writer(&mut self) -> mpsc::Sender<String> {
         self.write_tx.clone()
     }
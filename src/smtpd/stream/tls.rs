@@ -4,9 +4,54 @@
 // src/smtpd/stream/tls.rs
 // Stream of SMTPd.
 
+use super::common;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::task;
+use tokio_rustls::server::TlsStream;
+
 pub struct TlsSmtpStream {
+    pub server_name: Option<String>,
+    pub binding: Option<Vec<u8>>,
     pub write_tx: mpsc::Sender<String>,
-    pub read_rx: mpsc::Receiver<String>,
-    control: watch::Sender<SmtpStreamControl>,
-    handler: Option<task::JoinHandle<anyhow::Result<TlsStream<TcpStream>>>>,
+    pub read_rx: mpsc::Receiver<common::SmtpStreamRead>,
+    pub control: watch::Sender<common::SmtpStreamControl>,
+    pub handler: Option<task::JoinHandle<anyhow::Result<TlsStream<TcpStream>>>>,
+}
+
+impl common::SmtpStreamTrait for TlsSmtpStream {
+    async fn stop(&mut self) -> anyhow::Result<()> {
+        let _ = self.control.send(common::SmtpStreamControl::Shutdown);
+        let stream = match self.handler.take() {
+            Some(handler) => handler.await?,
+            None => return Ok(()),
+        };
+        let _ = stream?.shutdown().await;
+        Ok(())
+    }
+
+    fn start_utf8(&mut self) {
+        let _ = self.control.send(common::SmtpStreamControl::Utf8);
+    }
+
+    fn start_data(&mut self) {
+        let _ = self.control.send(common::SmtpStreamControl::Data);
+    }
+
+    fn start_auth(&mut self) {
+        let _ = self.control.send(common::SmtpStreamControl::Auth);
+    }
+
+    async fn read(&mut self) -> Option<common::SmtpStreamRead> {
+        self.read_rx.recv().await
+    }
+
+    fn pending(&self) -> bool {
+        !self.read_rx.is_empty()
+    }
+
+    fn get_writer(&mut self) -> mpsc::Sender<String> {
+        self.write_tx.clone()
+    }
 }
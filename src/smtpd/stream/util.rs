@@ -4,7 +4,6 @@
 // src/smtpd/stream/util.rs
 // Common method of of SMTPd stream.
 
-use tokio::io::WriteHalf;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::mpsc;
 
@@ -15,15 +14,66 @@ where
     while let Ok(mes) = rx.try_recv() {
         writer.write_all(mes.as_bytes()).await?;
     }
+    writer.flush().await?;
     Ok(())
 }
 
+pub async fn write_flush<S>(writer: &mut S, out: &mut Vec<u8>) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    if !out.is_empty() {
+        writer.write_all(out).await?;
+        writer.flush().await?;
+        out.clear();
+    }
+    Ok(())
+}
+
+// Input is pending while the session has unread lines or a complete
+// line is still buffered, replies are held back until then.
+pub fn check_pending<R: AsyncRead, T>(reader: &BufReader<R>, tx: &mpsc::Sender<T>) -> bool {
+    tx.capacity() < tx.max_capacity() || reader.buffer().windows(2).any(|w| w == b"\r\n")
+}
+
+#[derive(Clone)]
+pub struct UtilReply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl UtilReply {
+    // RFC 3463 status of the reply, from its class if none was given.
+    pub fn status(&self) -> String {
+        let class = char::from(b'0' + (self.code / 100) as u8);
+        self.lines
+            .first()
+            .and_then(|line| line.split_whitespace().next())
+            .filter(|status| {
+                let parts: Vec<&str> = status.split('.').collect();
+                parts.len() == 3
+                    && parts[0].len() == 1
+                    && parts[0].starts_with(class)
+                    && parts
+                        .iter()
+                        .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+            })
+            .map_or_else(|| format!("{}.0.0", class), str::to_string)
+    }
+
+    pub fn text(&self) -> String {
+        format!("{} {}", self.code, self.lines.join(" "))
+    }
+}
+
 pub enum UtilReadError {
     TooLong,
     NotAscii,
     NetError(std::io::Error),
 }
 
+// Read one CRLF terminated line into data. The partial line stays in data,
+// so the future can be cancelled and called again.
 pub async fn read_line_limit<R>(
     reader: &mut BufReader<R>,
     data: &mut Vec<u8>,
@@ -33,48 +83,120 @@ pub async fn read_line_limit<R>(
 where
     R: AsyncRead + Unpin,
 {
-    if !eight_bit {
-        let mut can_save = true;
-        loop {
-            let buf: std::io::Result<&[u8]> = reader.fill_buf().await;
-            let buf = match buf {
-                Ok(mes) => mes,
-                Err(e) => return Err(UtilReadError::NetError(e)),
-            };
-            if buf.is_empty() {
-                return Ok(0);
-            }
-            if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
-                let len = pos + 2;
-                if add_limit(data, &buf[..len], limit, can_save) && check_8bit(data) {
-                    reader.consume(len);
-                    return Ok(data.len());
-                } else {
-                    reader.consume(len);
+    loop {
+        let buf: std::io::Result<&[u8]> = reader.fill_buf().await;
+        let buf = match buf {
+            Ok(mes) => mes,
+            Err(e) => return Err(UtilReadError::NetError(e)),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match find_crlf(data, buf) {
+            Some(len) => {
+                add_limit(data, &buf[..len], limit);
+                reader.consume(len);
+                if check_limit(data, limit) {
+                    data.clear();
+                    return Err(UtilReadError::TooLong);
+                }
+                // The line is kept, the caller decides by its verb.
+                if !eight_bit && !check_8bit(data) {
                     return Err(UtilReadError::NotAscii);
                 }
+                return Ok(data.len());
+            }
+            None => {
+                let len = buf.len();
+                add_limit(data, buf, limit);
+                reader.consume(len);
             }
-            can_save = add_limit(data, buf, limit, can_save);
         }
-    } else {
-        return Err(UtilReadError::TooLong);
     }
 }
 
-fn add_limit(list: &mut Vec<u8>, buf: &[u8], limit: usize, can_save: bool) -> bool {
-    if check_limit(list, buf.len(), limit) || !can_save {
-        list.clear();
-        return false;
-    } else {
+// Read a reply, "250-" lines continue it and "250 " ends it.
+pub async fn read_reply<R>(reader: &mut BufReader<R>, limit: usize) -> anyhow::Result<UtilReply>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reply = UtilReply {
+        code: 0,
+        lines: Vec::new(),
+    };
+    loop {
+        let mut data = Vec::new();
+        match read_line_limit(reader, &mut data, true, limit).await {
+            Ok(0) => return Err(anyhow::anyhow!("connection closed")),
+            Ok(_) => {}
+            Err(UtilReadError::NetError(e)) => return Err(e.into()),
+            Err(_) => return Err(anyhow::anyhow!("reply line too long")),
+        }
+        let line = String::from_utf8_lossy(&data);
+        let line = line.trim_end_matches("\r\n");
+        let code: u16 = match line.get(..3).map(str::parse) {
+            Some(Ok(code)) if (200..600).contains(&code) => code,
+            _ => return Err(anyhow::anyhow!("malformed reply: {}", line)),
+        };
+        if reply.code != 0 && reply.code != code {
+            return Err(anyhow::anyhow!("inconsistent reply: {}", line));
+        }
+        reply.code = code;
+        reply.lines.push(line.get(4..).unwrap_or("").to_string());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(reply);
+        }
+    }
+}
+
+// Read at most count bytes into data.
+pub async fn read_count<R>(
+    reader: &mut BufReader<R>,
+    data: &mut Vec<u8>,
+    count: usize,
+) -> Result<usize, UtilReadError>
+where
+    R: AsyncRead + Unpin,
+{
+    let buf = match reader.fill_buf().await {
+        Ok(mes) => mes,
+        Err(e) => return Err(UtilReadError::NetError(e)),
+    };
+    let len = buf.len().min(count);
+    data.extend_from_slice(&buf[..len]);
+    reader.consume(len);
+    Ok(len)
+}
+
+fn find_crlf(data: &[u8], buf: &[u8]) -> Option<usize> {
+    let mut prev = data.last().copied();
+    for (i, &b) in buf.iter().enumerate() {
+        if b == b'\n' && prev == Some(b'\r') {
+            return Some(i + 1);
+        }
+        prev = Some(b);
+    }
+    None
+}
+
+// Keep at most limit + 1 bytes, the last one always being the latest byte
+// read, so an overlong line is still terminated correctly.
+fn add_limit(list: &mut Vec<u8>, buf: &[u8], limit: usize) {
+    let room = (limit + 1).saturating_sub(list.len());
+    if buf.len() <= room {
         list.extend_from_slice(buf);
-        return true;
+    } else {
+        list.extend_from_slice(&buf[..room]);
+        if let (Some(last), Some(byte)) = (list.last_mut(), buf.last()) {
+            *last = *byte;
+        }
     }
 }
 
-fn check_limit(list: &Vec<u8>, len: usize, limit: usize) -> bool {
-    list.len() + len > limit
+fn check_limit(list: &[u8], limit: usize) -> bool {
+    list.len() > limit
 }
 
-fn check_8bit(data: &Vec<u8>) -> bool {
+fn check_8bit(data: &[u8]) -> bool {
     data.iter().all(|&b| b.is_ascii())
 }
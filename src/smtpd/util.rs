@@ -5,26 +5,6 @@
 // src/smtpd/util.rs
 // Tools of SMTPd.
 
-use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
-use tokio::sync::mpsc;
-
-pub async fn write_multi_response(
-    writer: mpsc::Sender<String>,
-    lines: Vec<&str>,
-) -> anyhow::Result<()> {
-    let mut lines_string: Vec<String> = lines
-        .into_iter()
-        .map(|s| format!("250-{}\r\n", s))
-        .collect();
-    if let Some(last) = lines_string.last_mut() {
-        last.replace_range(3..4, " ");
-    }
-    for line in lines_string {
-        writer.send(line).await?;
-    }
-    Ok(())
-}
-
 pub fn remove_crlf(line: &mut String) {
     let len = line
         .char_indices()